@@ -0,0 +1,355 @@
+use libc::c_int;
+
+pub const ACCESS_ACL_XATTR: &[u8] = b"system.posix_acl_access";
+pub const DEFAULT_ACL_XATTR: &[u8] = b"system.posix_acl_default";
+
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AclEntry {
+    tag: u16,
+    perm: u16,
+    id: u32,
+}
+
+/// POSIX access control list, stored in the `system.posix_acl_*` extended
+/// attributes with the same binary layout used by the Linux kernel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// Build the minimal ACL equivalent to the permission bits of `mode`.
+    pub fn from_mode(mode: u16) -> Acl {
+        Acl {
+            entries: vec![
+                AclEntry {
+                    tag: ACL_USER_OBJ,
+                    perm: (mode >> 6) & 0o7,
+                    id: ACL_UNDEFINED_ID,
+                },
+                AclEntry {
+                    tag: ACL_GROUP_OBJ,
+                    perm: (mode >> 3) & 0o7,
+                    id: ACL_UNDEFINED_ID,
+                },
+                AclEntry {
+                    tag: ACL_OTHER,
+                    perm: mode & 0o7,
+                    id: ACL_UNDEFINED_ID,
+                },
+            ],
+        }
+    }
+
+    /// Parse and validate an ACL from its extended attribute value.
+    /// The ACL must hold exactly one owner, owning group and other entry, and
+    /// a mask entry whenever it has named user or group entries.
+    pub fn parse(bytes: &[u8]) -> Result<Acl, c_int> {
+        if bytes.len() < HEADER_SIZE || (bytes.len() - HEADER_SIZE) % ENTRY_SIZE != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        let version = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        if version != ACL_XATTR_VERSION {
+            return Err(libc::EOPNOTSUPP);
+        }
+
+        let mut entries: Vec<AclEntry> = bytes[HEADER_SIZE..]
+            .chunks(ENTRY_SIZE)
+            .map(|entry| AclEntry {
+                tag: u16::from_le_bytes(entry[0..2].try_into().unwrap()),
+                perm: u16::from_le_bytes(entry[2..4].try_into().unwrap()),
+                id: u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+            })
+            .collect();
+
+        for entry in &mut entries {
+            if entry.perm & !0o7 != 0 {
+                return Err(libc::EINVAL);
+            }
+            match entry.tag {
+                ACL_USER | ACL_GROUP => {
+                    if entry.id == ACL_UNDEFINED_ID {
+                        return Err(libc::EINVAL);
+                    }
+                }
+                ACL_USER_OBJ | ACL_GROUP_OBJ | ACL_MASK | ACL_OTHER => {
+                    entry.id = ACL_UNDEFINED_ID;
+                }
+                _ => return Err(libc::EINVAL),
+            }
+        }
+
+        entries.sort_by_key(|entry| (entry.tag, entry.id));
+        if entries
+            .windows(2)
+            .any(|pair| pair[0].tag == pair[1].tag && pair[0].id == pair[1].id)
+        {
+            return Err(libc::EINVAL);
+        }
+
+        let acl = Acl { entries };
+        let has_named = acl.entry(ACL_USER).is_some() || acl.entry(ACL_GROUP).is_some();
+        if acl.entry(ACL_USER_OBJ).is_none()
+            || acl.entry(ACL_GROUP_OBJ).is_none()
+            || acl.entry(ACL_OTHER).is_none()
+            || (has_named && acl.entry(ACL_MASK).is_none())
+        {
+            return Err(libc::EINVAL);
+        }
+
+        Ok(acl)
+    }
+
+    /// Serialize the ACL into its extended attribute value.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.entries.len() * ENTRY_SIZE);
+        bytes.extend_from_slice(&ACL_XATTR_VERSION.to_le_bytes());
+        for entry in &self.entries {
+            bytes.extend_from_slice(&entry.tag.to_le_bytes());
+            bytes.extend_from_slice(&entry.perm.to_le_bytes());
+            bytes.extend_from_slice(&entry.id.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn entry(&self, tag: u16) -> Option<&AclEntry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    fn entry_mut(&mut self, tag: u16) -> Option<&mut AclEntry> {
+        self.entries.iter_mut().find(|entry| entry.tag == tag)
+    }
+
+    /// Whether the ACL holds nothing beyond what the permission bits can express.
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    /// Permission bits matching the ACL, where the group bits reflect the mask entry when present.
+    pub fn mode_bits(&self) -> u16 {
+        let perm = |tag| self.entry(tag).map_or(0, |entry| entry.perm);
+        let group = match self.entry(ACL_MASK) {
+            Some(mask) => mask.perm,
+            None => perm(ACL_GROUP_OBJ),
+        };
+        (perm(ACL_USER_OBJ) << 6) | (group << 3) | perm(ACL_OTHER)
+    }
+
+    /// Update the ACL after a `chmod`, keeping it in sync with the permission bits.
+    /// The group bits update the mask entry when present, or the owning group otherwise.
+    pub fn apply_mode(&mut self, mode: u16) {
+        let group_tag = if self.entry(ACL_MASK).is_some() {
+            ACL_MASK
+        } else {
+            ACL_GROUP_OBJ
+        };
+
+        for (tag, perm) in [
+            (ACL_USER_OBJ, (mode >> 6) & 0o7),
+            (group_tag, (mode >> 3) & 0o7),
+            (ACL_OTHER, mode & 0o7),
+        ] {
+            if let Some(entry) = self.entry_mut(tag) {
+                entry.perm = perm;
+            }
+        }
+    }
+
+    /// Build the access ACL of an entry created under a directory with this default ACL.
+    /// The permissions requested by the creation `mode` limit the inherited entries,
+    /// in place of the umask.
+    pub fn inherit(&self, mode: u16) -> Acl {
+        let mut acl = self.clone();
+        let group_tag = if acl.entry(ACL_MASK).is_some() {
+            ACL_MASK
+        } else {
+            ACL_GROUP_OBJ
+        };
+
+        for (tag, perm) in [
+            (ACL_USER_OBJ, (mode >> 6) & 0o7),
+            (group_tag, (mode >> 3) & 0o7),
+            (ACL_OTHER, mode & 0o7),
+        ] {
+            if let Some(entry) = acl.entry_mut(tag) {
+                entry.perm &= perm;
+            }
+        }
+        acl
+    }
+
+    /// Check whether the ACL grants every permission in `mask` to a user.
+    /// Follows the POSIX.1e access check: the owner entry applies to the owner,
+    /// then named users, then any matching group entry, and finally other.
+    /// Named user and group entries are limited by the mask entry.
+    pub fn allows(
+        &self,
+        owner_uid: u32,
+        owner_gid: u32,
+        uid: u32,
+        gids: &[u32],
+        mask: u16,
+    ) -> bool {
+        let grants = |perm: u16| perm & mask == mask;
+        let masked = |perm: u16| match self.entry(ACL_MASK) {
+            Some(mask_entry) => perm & mask_entry.perm,
+            None => perm,
+        };
+
+        if uid == owner_uid {
+            return self
+                .entry(ACL_USER_OBJ)
+                .is_some_and(|entry| grants(entry.perm));
+        }
+
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| entry.tag == ACL_USER && entry.id == uid)
+        {
+            return grants(masked(entry.perm));
+        }
+
+        let mut group_matched = false;
+        for entry in &self.entries {
+            let matches = match entry.tag {
+                ACL_GROUP_OBJ => gids.contains(&owner_gid),
+                ACL_GROUP => gids.contains(&entry.id),
+                _ => false,
+            };
+            if matches {
+                if grants(masked(entry.perm)) {
+                    return true;
+                }
+                group_matched = true;
+            }
+        }
+        if group_matched {
+            return false;
+        }
+
+        self.entry(ACL_OTHER)
+            .is_some_and(|entry| grants(entry.perm))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(entries: &[(u16, u16, u32)]) -> Acl {
+        Acl {
+            entries: entries
+                .iter()
+                .map(|&(tag, perm, id)| AclEntry { tag, perm, id })
+                .collect(),
+        }
+    }
+
+    /// ACL granting the owner everything, user 2000 read and write limited by a
+    /// read-only mask, the owning group read and others nothing.
+    fn named_user_acl() -> Acl {
+        acl(&[
+            (ACL_USER_OBJ, 0o7, ACL_UNDEFINED_ID),
+            (ACL_USER, 0o6, 2000),
+            (ACL_GROUP_OBJ, 0o4, ACL_UNDEFINED_ID),
+            (ACL_MASK, 0o4, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 0o0, ACL_UNDEFINED_ID),
+        ])
+    }
+
+    #[test]
+    fn parse_reads_back_serialized_acls() {
+        let parsed = Acl::parse(&named_user_acl().to_bytes()).unwrap();
+        assert_eq!(parsed, named_user_acl());
+        assert!(!parsed.is_minimal());
+        assert!(Acl::parse(&Acl::from_mode(0o640).to_bytes())
+            .unwrap()
+            .is_minimal());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_acls() {
+        let mut bytes = named_user_acl().to_bytes();
+        bytes.pop();
+        assert_eq!(Acl::parse(&bytes), Err(libc::EINVAL));
+
+        let mut bytes = named_user_acl().to_bytes();
+        bytes[0] = 1;
+        assert_eq!(Acl::parse(&bytes), Err(libc::EOPNOTSUPP));
+
+        // Named entries require a mask entry
+        let without_mask = acl(&[
+            (ACL_USER_OBJ, 0o7, ACL_UNDEFINED_ID),
+            (ACL_USER, 0o6, 2000),
+            (ACL_GROUP_OBJ, 0o4, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 0o0, ACL_UNDEFINED_ID),
+        ]);
+        assert_eq!(Acl::parse(&without_mask.to_bytes()), Err(libc::EINVAL));
+
+        let duplicated = acl(&[
+            (ACL_USER_OBJ, 0o7, ACL_UNDEFINED_ID),
+            (ACL_USER_OBJ, 0o6, ACL_UNDEFINED_ID),
+            (ACL_GROUP_OBJ, 0o4, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 0o0, ACL_UNDEFINED_ID),
+        ]);
+        assert_eq!(Acl::parse(&duplicated.to_bytes()), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn allows_follows_the_posix_access_check() {
+        let acl = named_user_acl();
+        let read = libc::R_OK as u16;
+        let write = libc::W_OK as u16;
+
+        // The owner entry applies to the owner, even when other entries would match
+        assert!(acl.allows(1000, 1000, 1000, &[1000], read | write));
+
+        // Named users are limited by the mask
+        assert!(acl.allows(1000, 1000, 2000, &[3000], read));
+        assert!(!acl.allows(1000, 1000, 2000, &[3000], write));
+
+        // A matching group entry that denies the access does not fall back to other
+        assert!(acl.allows(1000, 1000, 3000, &[1000], read));
+        assert!(!acl.allows(1000, 1000, 3000, &[1000], write));
+        assert!(!acl.allows(1000, 1000, 3000, &[3000], read));
+    }
+
+    #[test]
+    fn mode_changes_update_the_mask_entry() {
+        let mut acl = named_user_acl();
+        assert_eq!(acl.mode_bits(), 0o740);
+
+        acl.apply_mode(0o765);
+        assert_eq!(acl.mode_bits(), 0o765);
+        assert_eq!(acl.entry(ACL_GROUP_OBJ).unwrap().perm, 0o4);
+        assert_eq!(acl.entry(ACL_MASK).unwrap().perm, 0o6);
+
+        let mut minimal = Acl::from_mode(0o640);
+        minimal.apply_mode(0o705);
+        assert_eq!(minimal, Acl::from_mode(0o705));
+    }
+
+    #[test]
+    fn inherit_limits_the_default_acl_by_the_creation_mode() {
+        let inherited = named_user_acl().inherit(0o600);
+        assert_eq!(inherited.mode_bits(), 0o600);
+        assert_eq!(inherited.entry(ACL_USER).unwrap().perm, 0o6);
+        assert_eq!(inherited.entry(ACL_MASK).unwrap().perm, 0o0);
+        assert!(!inherited.allows(1000, 1000, 2000, &[3000], libc::R_OK as u16));
+    }
+}
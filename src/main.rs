@@ -1,20 +1,28 @@
 #![allow(warnings)]
 
+mod acl;
+mod quota;
 mod utils;
 
-use crate::utils::{system_time_from_time, time_from_system_time, time_now};
+use crate::acl::{Acl, ACCESS_ACL_XATTR, DEFAULT_ACL_XATTR};
+use crate::quota::{QuotaId, Quotas, DEFAULT_GRACE_PERIOD};
+use crate::utils::{supplementary_groups, system_time_from_time, time_from_system_time, time_now};
 use clap::{Arg, ArgAction, Command};
+use fuser::{consts, fuse_forget_one, KernelConfig, MountOption, ReplyEntry, FUSE_ROOT_ID};
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyOpen, ReplyWrite, Request, TimeOrNow,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyLseek, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
+    Request, TimeOrNow,
 };
-use fuser::{MountOption, ReplyEntry, FUSE_ROOT_ID};
 use libc::c_int;
 use log::{debug, LevelFilter};
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::rc::Rc;
 use std::time::{Duration, SystemTime};
 
 const DIR_MODE: u8 = 0;
@@ -22,6 +30,11 @@ const FILE_MODE: u8 = 1;
 
 const BLOCK_SIZE: u32 = 512;
 
+const CHUNK_SIZE: u64 = 4096; // Size in bytes of each file content chunk
+
+const FIRST_ENTRY_COOKIE: u64 = 3; // Readdir cookies 1 and 2 belong to "." and ".."
+const DIRECTORY_ENTRY_APPARENT_SIZE: u64 = 20; // Size in bytes reported per directory entry
+
 const FMODE_EXEC: i32 = 0x20;
 
 static mut INODE_SERIAL_NUMER: u64 = 2;
@@ -47,6 +60,11 @@ fn get_max_memory() -> u64 {
 
 const MAX_NODE_NAME_LENGTH: usize = 255; // Max file name length in bytes
 
+const XATTR_NAME_MAX_LENGTH: usize = 255; // Max extended attribute name length in bytes
+const XATTR_VALUE_MAX_LENGTH: usize = 65536; // Max extended attribute value length in bytes
+const QUOTA_XATTR: &[u8] = b"user.vffs.quota"; // Read-only attribute reporting the quotas of an inode owner
+const PROJECT_XATTR: &[u8] = b"user.vffs.project"; // Project quota id of an inode, inherited by new entries
+
 static mut MAX_FILE_SIZE: u64 = 0; // Max file size in MB
 
 fn set_max_file_size(mb: u64) {
@@ -59,17 +77,39 @@ fn get_max_file_size() -> u64 {
     unsafe { MAX_FILE_SIZE }
 }
 
+static mut MAX_INODES: u64 = u64::MAX; // Max number of inodes, unlimited by default
+
+fn set_max_inodes(count: u64) {
+    unsafe {
+        MAX_INODES = count;
+    }
+}
+
+fn get_max_inodes() -> u64 {
+    unsafe { MAX_INODES }
+}
+
 #[derive(Debug, Clone)]
 pub enum InodeData {
     File(File),
     Directory(Directory),
+    Symlink(Symlink),
+    Fifo(SpecialFile),
+    Socket(SpecialFile),
+    CharDevice(SpecialFile),
+    BlockDevice(SpecialFile),
 }
 
-impl From<InodeData> for FileType {
-    fn from(kind: InodeData) -> Self {
+impl From<&InodeData> for FileType {
+    fn from(kind: &InodeData) -> Self {
         match kind {
             InodeData::File(_) => FileType::RegularFile,
             InodeData::Directory(_) => FileType::Directory,
+            InodeData::Symlink(_) => FileType::Symlink,
+            InodeData::Fifo(_) => FileType::NamedPipe,
+            InodeData::Socket(_) => FileType::Socket,
+            InodeData::CharDevice(_) => FileType::CharDevice,
+            InodeData::BlockDevice(_) => FileType::BlockDevice,
         }
     }
 }
@@ -77,14 +117,18 @@ impl From<InodeData> for FileType {
 struct VFFS {
     inodes: HashMap<u64, Inode>,
     size: u64,
+    quotas: Quotas,
 }
 
 impl VFFS {
-    fn new(mount: &String) -> VFFS {
-        let root = Inode::new(DIR_MODE, mount.clone(), FUSE_ROOT_ID);
-        let mut inodes = HashMap::new();
-        inodes.insert(FUSE_ROOT_ID, root);
-        VFFS { inodes, size: 0 }
+    fn new(mount: &String, quotas: Quotas) -> VFFS {
+        let mut vffs = VFFS {
+            inodes: HashMap::new(),
+            size: 0,
+            quotas,
+        };
+        vffs.append_inode(Inode::new(DIR_MODE, mount.clone(), FUSE_ROOT_ID));
+        vffs
     }
 
     fn lookup_node(&self, id: u64) -> Result<&Inode, c_int> {
@@ -117,68 +161,1160 @@ impl VFFS {
 
     /// Append a new inode to the filesystem.
     /// The method adds the inode to the internal inode map,
-    /// adding its size to the total filesystem size.
-    fn append_inode(&mut self, inode: Inode) {
-        self.size += inode.size;
+    /// charging its metadata and the stored bytes of a file to the total filesystem size.
+    /// The quotas of its owners are charged for every chunk of a file, even shared ones.
+    fn append_inode(&mut self, mut inode: Inode) {
+        inode.charged_metadata = inode.metadata_size();
+        self.size += inode.charged_metadata;
+        if let InodeData::File(file) = &inode.data {
+            inode.charged_data = file.allocated_size();
+            self.size += file.unique_size();
+        }
+        self.quotas.charge(
+            &inode.quota_ids(),
+            inode.quota_bytes() as i64,
+            1,
+            time_now().0,
+        );
         self.inodes.insert(inode.id, inode);
     }
 
     /// Remove an inode from the filesystem by its ID.
-    /// The method subtracts the inode size from the total filesystem size
-    /// and removes the inode from the internal inode map.
+    /// The method releases the metadata charged for the inode and the stored bytes
+    /// of a file from the total filesystem size and removes the inode from the internal inode map.
     fn remove_inode(&mut self, inode_id: u64) {
         if let Some(inode) = self.inodes.remove(&inode_id) {
-            self.size -= inode.size;
+            self.size -= inode.charged_metadata;
+            if let InodeData::File(file) = &inode.data {
+                self.size -= file.unique_size();
+            }
+            self.quotas.charge(
+                &inode.quota_ids(),
+                -(inode.quota_bytes() as i64),
+                -1,
+                time_now().0,
+            );
+        }
+    }
+
+    /// Bring the metadata charged for an inode up to date after its name,
+    /// extended attributes or directory entries changed.
+    fn account_metadata(&mut self, inode_id: u64) {
+        if let Some(inode) = self.inodes.get_mut(&inode_id) {
+            let charge = inode.metadata_size();
+            let size_diff = charge as i64 - inode.charged_metadata as i64;
+            self.size = (self.size as i64 + size_diff) as u64;
+            self.quotas
+                .charge(&inode.quota_ids(), size_diff, 0, time_now().0);
+            inode.charged_metadata = charge;
+        }
+    }
+
+    /// Check that a new inode and its entry named `name` in `parent` fit under the
+    /// memory limit and under the quotas of their owners.
+    fn check_new_inode(&self, parent: u64, inode: &Inode, name: &str) -> Result<(), c_int> {
+        let growth = [(parent, Directory::entry_size(name) as i64)];
+        self.check_metadata_growth(&growth, Some(inode))
+    }
+
+    /// Check that the metadata of existing inodes can change by the given bytes,
+    /// along with a new inode when one is created, without going over the memory limit.
+    /// The inodes whose metadata grows, and the new inode, must also fit under the
+    /// quotas of their owners.
+    fn check_metadata_growth(
+        &self,
+        growth: &[(u64, i64)],
+        new_inode: Option<&Inode>,
+    ) -> Result<(), c_int> {
+        let mut total: i64 = growth.iter().map(|(_, bytes)| bytes).sum();
+        if let Some(inode) = new_inode {
+            total += inode.metadata_size() as i64;
+        }
+        if total > 0 {
+            if let Err(err) = self.check_memory(total as u64) {
+                return Err(err);
+            }
+        }
+
+        // An inode may appear several times, as when both entries of a rename are in the same directory
+        let mut growth_by_inode: BTreeMap<u64, i64> = BTreeMap::new();
+        for (inode_id, bytes) in growth {
+            *growth_by_inode.entry(*inode_id).or_default() += bytes;
+        }
+        for (inode_id, bytes) in growth_by_inode {
+            if bytes <= 0 {
+                continue;
+            }
+            let inode = match self.lookup_node(inode_id) {
+                Ok(inode) => inode,
+                Err(err) => return Err(err),
+            };
+            if let Err(err) = self
+                .quotas
+                .check(&inode.quota_ids(), bytes as u64, 0, time_now().0)
+            {
+                return Err(err);
+            }
+        }
+
+        match new_inode {
+            Some(inode) => {
+                self.quotas
+                    .check(&inode.quota_ids(), inode.metadata_size(), 1, time_now().0)
+            }
+            None => Ok(()),
         }
     }
 
-    /// Write data to a file inode.
+    /// Change in the metadata of each inode involved in moving the entry `name` of
+    /// `parent` to `new_name` in `new_parent`, or in exchanging both entries.
+    /// Moving an entry over an existing one reuses the entry of the replaced inode,
+    /// whose release is not counted, and a whiteout takes over the source entry.
+    fn rename_growth(
+        (parent, name, source_id): (u64, &str, u64),
+        (new_parent, new_name, target_id): (u64, &str, Option<u64>),
+        exchange: bool,
+        whiteout: bool,
+    ) -> Vec<(u64, i64)> {
+        let name_diff = new_name.len() as i64 - name.len() as i64;
+        let mut growth = vec![(source_id, name_diff)];
+
+        if exchange {
+            growth.extend(target_id.map(|target_id| (target_id, -name_diff)));
+        } else {
+            if !whiteout {
+                growth.push((parent, -(Directory::entry_size(name) as i64)));
+            }
+            if target_id.is_none() {
+                growth.push((new_parent, Directory::entry_size(new_name) as i64));
+            }
+        }
+
+        growth
+    }
+
+    /// Owners of an inode whose quotas change when it gets a new user or group,
+    /// as pairs of the current and the new owner.
+    fn changed_quota_ids(
+        inode: &Inode,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Vec<(QuotaId, QuotaId)> {
+        inode
+            .quota_ids()
+            .into_iter()
+            .map(|old_id| {
+                let new_id = match old_id {
+                    QuotaId::User(old_uid) => QuotaId::User(uid.unwrap_or(old_uid)),
+                    QuotaId::Group(old_gid) => QuotaId::Group(gid.unwrap_or(old_gid)),
+                    QuotaId::Project(project) => QuotaId::Project(project),
+                };
+                (old_id, new_id)
+            })
+            .filter(|(old_id, new_id)| old_id != new_id)
+            .collect()
+    }
+
+    /// Check that the bytes and inode charged for an inode fit under the quotas
+    /// of the owners it gets with a new user or group.
+    fn check_quota_transfer(
+        &self,
+        inode_id: u64,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<(), c_int> {
+        let inode = match self.lookup_node(inode_id) {
+            Ok(inode) => inode,
+            Err(err) => return Err(err),
+        };
+
+        let gaining: Vec<QuotaId> = VFFS::changed_quota_ids(inode, uid, gid)
+            .into_iter()
+            .map(|(_, new_id)| new_id)
+            .collect();
+        self.quotas
+            .check(&gaining, inode.quota_bytes(), 1, time_now().0)
+    }
+
+    /// Move the bytes and inode charged for an inode to the quotas of its new owners,
+    /// which `check_quota_transfer` must have accepted first.
+    fn transfer_quota(&mut self, inode_id: u64, uid: Option<u32>, gid: Option<u32>) {
+        let (changed, bytes) = match self.lookup_node(inode_id) {
+            Ok(inode) => (
+                VFFS::changed_quota_ids(inode, uid, gid),
+                inode.quota_bytes() as i64,
+            ),
+            Err(_) => return,
+        };
+
+        let now = time_now().0;
+        for (old_id, new_id) in changed {
+            self.quotas.charge(&[old_id], -bytes, -1, now);
+            self.quotas.charge(&[new_id], bytes, 1, now);
+        }
+    }
+
+    /// Project quota id inherited by entries created under `parent`.
+    fn inherited_project(&self, parent: u64) -> u32 {
+        match self.lookup_node(parent) {
+            Ok(parent_inode) => parent_inode.project,
+            Err(_) => 0,
+        }
+    }
+
+    /// Tag an inode with a project quota id, along with everything below it when
+    /// it is a directory, moving the bytes and inodes charged for each of them to
+    /// the new project. A project id of 0 removes the tag.
+    fn set_project(&mut self, inode_id: u64, project: u32) -> Result<(), c_int> {
+        if let Err(err) = self.lookup_node(inode_id) {
+            return Err(err);
+        }
+
+        let now = time_now().0;
+        let mut pending = vec![inode_id];
+        while let Some(id) = pending.pop() {
+            let inode = match self.inodes.get_mut(&id) {
+                Some(inode) => inode,
+                None => continue,
+            };
+            if let InodeData::Directory(dir) = &inode.data {
+                pending.extend(dir.nodes().map(|node| node.0));
+            }
+            if inode.project == project {
+                continue;
+            }
+
+            let bytes = inode.quota_bytes() as i64;
+            if inode.project != 0 {
+                self.quotas
+                    .charge(&[QuotaId::Project(inode.project)], -bytes, -1, now);
+            }
+            if project != 0 {
+                self.quotas
+                    .charge(&[QuotaId::Project(project)], bytes, 1, now);
+            }
+            inode.project = project;
+            inode.metadata_change_at = time_now();
+        }
+        Ok(())
+    }
+
+    /// Check that `cost` more bytes fit under the memory limit.
+    fn check_memory(&self, cost: u64) -> Result<(), c_int> {
+        if self.size + cost > get_max_memory() {
+            Err(libc::ENOSPC)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Drop one link of a non-directory inode, after one of its entries was removed.
+    /// The inode is only removed from the filesystem, releasing its bytes,
+    /// once its last link is gone and nothing else references it.
+    fn release_link(&mut self, inode_id: u64) {
+        if let Ok(inode) = self.lookup_node_mut(inode_id) {
+            inode.hardlinks = inode.hardlinks.saturating_sub(1);
+            inode.metadata_change_at = time_now();
+        }
+
+        self.reclaim_if_unused(inode_id);
+    }
+
+    /// Remove an inode from the filesystem once it is unreachable.
+    /// That is the case when no directory entry links to it, the kernel
+    /// forgot every lookup of it and no open file handle points to it.
+    /// Until then an orphaned inode keeps its data and can still be used.
+    fn reclaim_if_unused(&mut self, inode_id: u64) {
+        let unused = match self.lookup_node(inode_id) {
+            Ok(inode) => inode.hardlinks == 0 && inode.lookups == 0 && inode.open_handles == 0,
+            Err(_) => false,
+        };
+
+        if unused {
+            self.remove_inode(inode_id);
+        }
+    }
+
+    /// Apply a change to the contents of a file inode, enforcing the memory limit
+    /// and the quotas of its owners. `cost` gives the bytes the change needs to
+    /// allocate in memory and the bytes it adds to the chunks of the file, which are
+    /// what the quotas are charged, shared chunks included. `change` applies it and
+    /// returns the change in allocated bytes, which is charged to the filesystem size.
+    /// Updating the size and timestamps of the inode is left to the caller.
+    fn change_file_data(
+        &mut self,
+        inode_id: u64,
+        cost: impl FnOnce(&File) -> (u64, u64),
+        change: impl FnOnce(&mut File) -> i64,
+    ) -> Result<(), c_int> {
+        let ((new_bytes, new_quota_bytes), quota_ids) = match self.lookup_node(inode_id) {
+            Ok(inode) => match &inode.data {
+                InodeData::File(virtual_file) => (cost(virtual_file), inode.quota_ids()),
+                InodeData::Directory(_) => return Err(libc::EISDIR),
+                _ => return Err(libc::EINVAL),
+            },
+            Err(err) => return Err(err),
+        };
+
+        if let Err(err) = self.check_memory(new_bytes) {
+            return Err(err);
+        }
+        if let Err(err) = self
+            .quotas
+            .check(&quota_ids, new_quota_bytes, 0, time_now().0)
+        {
+            return Err(err);
+        }
+
+        let (size_diff, quota_diff) = match self.lookup_node_mut(inode_id) {
+            Ok(inode) => match &mut inode.data {
+                InodeData::File(virtual_file) => {
+                    let size_diff = change(virtual_file);
+                    let allocated = virtual_file.allocated_size();
+                    let quota_diff = allocated as i64 - inode.charged_data as i64;
+                    inode.charged_data = allocated;
+                    (size_diff, quota_diff)
+                }
+                _ => return Err(libc::EINVAL),
+            },
+            Err(err) => return Err(err),
+        };
+
+        self.size = (self.size as i64 + size_diff) as u64;
+        self.quotas.charge(&quota_ids, quota_diff, 0, time_now().0);
+
+        Ok(())
+    }
+
+    /// Write data to a file inode at the given offset.
     /// The method validates the size of the data to be written against
     /// the maximum file size and available memory,
-    /// and writes the data to the file's data buffer.
+    /// and places the data in the file's chunks starting at `offset`.
+    /// Writing past the end of the file leaves a hole, which reads back as zeros
+    /// and is not charged against the memory limit.
     /// If successful, it updates the total filesystem size.
-    fn write_file_data(&mut self, inode_id: u64, data: &[u8]) -> Result<(), c_int> {
-        let data_len = data.len() as u64;
+    fn write_file_data(&mut self, inode_id: u64, offset: u64, data: &[u8]) -> Result<(), c_int> {
+        let end = offset + data.len() as u64;
+        if end > get_max_file_size() {
+            return Err(libc::EFBIG);
+        }
 
-        let size_diff: i64;
+        if let Err(err) = self.change_file_data(
+            inode_id,
+            |virtual_file| {
+                (
+                    virtual_file.write_cost(offset, end),
+                    virtual_file.unallocated_bytes_in(offset, end),
+                )
+            },
+            |virtual_file| virtual_file.write_at(offset, data),
+        ) {
+            return Err(err);
+        }
 
+        if let Ok(inode) = self.lookup_node_mut(inode_id) {
+            inode.size = std::cmp::max(inode.size, end);
+            inode.update_changes();
+        }
+
+        Ok(())
+    }
+
+    /// Truncate or extend a file inode to `new_size` bytes.
+    /// Shrinking releases the chunks past the new end, while extending leaves
+    /// a hole that reads back as zeros, so it is only limited by the maximum
+    /// file size. The memory limit is only checked when the last chunk is
+    /// shared with another file and has to be copied before being cut.
+    /// If successful, it updates the total filesystem size.
+    fn truncate_file_data(&mut self, inode_id: u64, new_size: u64) -> Result<(), c_int> {
+        if new_size > get_max_file_size() {
+            return Err(libc::EFBIG);
+        }
+
+        if let Err(err) = self.change_file_data(
+            inode_id,
+            |virtual_file| (virtual_file.zero_cost(new_size, u64::MAX), 0),
+            |virtual_file| virtual_file.truncate(new_size),
+        ) {
+            return Err(err);
+        }
+
+        if let Ok(inode) = self.lookup_node_mut(inode_id) {
+            inode.size = new_size;
+            inode.update_changes();
+        }
+
+        Ok(())
+    }
+
+    /// Manipulate the allocated space of a file inode, following `fallocate(2)`.
+    /// Without flags the `[offset, offset + length)` range is preallocated and the
+    /// file is extended to cover it, while `FALLOC_FL_KEEP_SIZE` preallocates without
+    /// changing the file size. `FALLOC_FL_PUNCH_HOLE` releases the range and
+    /// `FALLOC_FL_ZERO_RANGE` makes it read back as zeros while keeping it allocated.
+    /// If successful, it updates the total filesystem size.
+    fn fallocate_file_data(
+        &mut self,
+        inode_id: u64,
+        offset: u64,
+        length: u64,
+        mode: i32,
+    ) -> Result<(), c_int> {
+        let keep_size = mode & libc::FALLOC_FL_KEEP_SIZE != 0;
+        let punch_hole = mode & libc::FALLOC_FL_PUNCH_HOLE != 0;
+        let zero_range = mode & libc::FALLOC_FL_ZERO_RANGE != 0;
+
+        if mode
+            & !(libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_ZERO_RANGE)
+            != 0
         {
-            let inode = match self.lookup_node_mut(inode_id) {
+            return Err(libc::EOPNOTSUPP);
+        }
+
+        // Punching a hole must never change the file size, and cannot be combined with zeroing
+        if punch_hole && (!keep_size || zero_range) {
+            return Err(libc::EOPNOTSUPP);
+        }
+
+        // Preallocating past the maximum file size is refused even when the size is kept
+        let end = offset + length;
+        if !punch_hole && end > get_max_file_size() {
+            return Err(libc::EFBIG);
+        }
+
+        if let Err(err) = self.change_file_data(
+            inode_id,
+            |virtual_file| {
+                let mut new_bytes = virtual_file.zero_cost(offset, end);
+                let mut new_quota_bytes = 0;
+                if !punch_hole {
+                    new_quota_bytes = virtual_file.unallocated_bytes_in(offset, end);
+                    new_bytes += new_quota_bytes;
+                }
+                if zero_range {
+                    new_bytes += virtual_file.shared_bytes_in(offset, end);
+                }
+                (new_bytes, new_quota_bytes)
+            },
+            |virtual_file| {
+                if punch_hole {
+                    virtual_file.punch_hole(offset, end)
+                } else if zero_range {
+                    virtual_file.punch_hole(offset, end) + virtual_file.allocate(offset, end)
+                } else {
+                    virtual_file.allocate(offset, end)
+                }
+            },
+        ) {
+            return Err(err);
+        }
+
+        if let Ok(inode) = self.lookup_node_mut(inode_id) {
+            let extends = !keep_size && end > inode.size;
+            if extends {
+                inode.size = end;
+            }
+            if extends || punch_hole || zero_range {
+                inode.update_changes();
+            } else {
+                inode.metadata_change_at = time_now();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy up to `length` bytes from a source file inode into a target file inode.
+    /// Whole chunks are shared between both files rather than duplicated, so the
+    /// copy is only charged against the memory limit for the partial chunks at its
+    /// edges, and later for any chunk either file writes to.
+    /// Returns the number of bytes copied, which is cut short at the end of the source.
+    fn copy_file_data(
+        &mut self,
+        source_id: u64,
+        source_offset: u64,
+        target_id: u64,
+        offset: u64,
+        length: u64,
+    ) -> Result<u64, c_int> {
+        let (source, length) = {
+            let inode = match self.lookup_node(source_id) {
                 Ok(inode) => inode,
                 Err(err) => return Err(err),
             };
 
-            match &mut inode.data {
+            match &inode.data {
                 InodeData::File(virtual_file) => {
-                    let old_size = inode.size;
-                    virtual_file.write_date(data);
+                    if source_offset >= inode.size {
+                        return Ok(0);
+                    }
 
-                    let final_size = old_size + data_len;
+                    let length = std::cmp::min(length, inode.size - source_offset);
+                    (
+                        virtual_file.slice(source_offset, source_offset + length),
+                        length,
+                    )
+                }
+                InodeData::Directory(_) => return Err(libc::EISDIR),
+                _ => return Err(libc::EINVAL),
+            }
+        };
 
-                    if final_size > get_max_file_size() {
-                        return Err(libc::EFBIG);
-                    }
+        // A file can only be copied onto itself between ranges that do not overlap
+        if source_id == target_id
+            && source_offset < offset + length
+            && offset < source_offset + length
+        {
+            return Err(libc::EINVAL);
+        }
+
+        let end = offset + length;
+        if end > get_max_file_size() {
+            return Err(libc::EFBIG);
+        }
+
+        if let Err(err) = self.change_file_data(
+            target_id,
+            |virtual_file| {
+                (
+                    virtual_file.copy_cost(source_offset, offset, length),
+                    virtual_file.copy_growth(&source, source_offset, offset, length),
+                )
+            },
+            |virtual_file| virtual_file.copy_from(&source, source_offset, offset, length),
+        ) {
+            return Err(err);
+        }
+
+        if let Ok(inode) = self.lookup_node_mut(target_id) {
+            inode.size = std::cmp::max(inode.size, end);
+            inode.update_changes();
+        }
+
+        Ok(length)
+    }
+
+    /// Check whether an extended attribute can be accessed by the requesting user.
+    /// Only the `user`, `trusted` and `security` namespaces are supported, along with
+    /// the POSIX ACLs in `system`: `trusted` attributes are reserved to root, `user`
+    /// attributes can only be attached to regular files and directories and require
+    /// read or write permission on them, writing `security` attributes or ACLs requires
+    /// owning the inode, and default ACLs only apply to directories.
+    fn check_xattr_access(
+        uid: u32,
+        gids: &[u32],
+        inode: &Inode,
+        name: &[u8],
+        write: bool,
+    ) -> Result<(), c_int> {
+        if name.starts_with(b"user.") {
+            if !inode.is_file() && !inode.is_directory() {
+                return Err(if write { libc::EPERM } else { libc::ENODATA });
+            }
+            // Like for its entries, only the owners of a sticky directory may change its attributes
+            let sticky = inode.is_directory() && inode.mode & libc::S_ISVTX as u16 != 0;
+            if write && sticky && uid != 0 && uid != inode.uid {
+                return Err(libc::EPERM);
+            }
+            let mask = if write { libc::W_OK } else { libc::R_OK };
+            if !VFFS::check_permission(inode, uid, gids, mask) {
+                return Err(libc::EACCES);
+            }
+            Ok(())
+        } else if name.starts_with(b"trusted.") {
+            if uid != 0 {
+                return Err(if write { libc::EPERM } else { libc::ENODATA });
+            }
+            Ok(())
+        } else if name.starts_with(b"security.") {
+            if write && uid != 0 && uid != inode.uid {
+                return Err(libc::EPERM);
+            }
+            Ok(())
+        } else if name == ACCESS_ACL_XATTR || name == DEFAULT_ACL_XATTR {
+            if name == DEFAULT_ACL_XATTR && !inode.is_directory() {
+                return Err(if write { libc::EACCES } else { libc::ENODATA });
+            }
+            if write && uid != 0 && uid != inode.uid {
+                return Err(libc::EPERM);
+            }
+            Ok(())
+        } else {
+            Err(libc::EOPNOTSUPP)
+        }
+    }
 
-                    size_diff = final_size as i64 - old_size as i64;
+    /// Set an extended attribute of an inode.
+    /// The `flags` follow `setxattr(2)`: `XATTR_CREATE` fails if the attribute
+    /// already exists and `XATTR_REPLACE` fails if it does not.
+    /// ACLs are validated before being stored, and an access ACL also updates the
+    /// permission bits of the inode. An access ACL that the permission bits can fully
+    /// express is not stored at all.
+    /// The attribute name and value are charged against the memory limit.
+    fn set_xattr(
+        &mut self,
+        uid: u32,
+        gids: &[u32],
+        inode_id: u64,
+        name: &[u8],
+        value: &[u8],
+        flags: i32,
+    ) -> Result<(), c_int> {
+        // The quota report is computed on demand and cannot be changed
+        if name == QUOTA_XATTR {
+            return Err(libc::EPERM);
+        }
+
+        // The project quota id is kept in the inode, and only root may change it
+        if name == PROJECT_XATTR {
+            if uid != 0 {
+                return Err(libc::EPERM);
+            }
+            let project = match std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+            {
+                Some(project) => project,
+                None => return Err(libc::EINVAL),
+            };
+            return self.set_project(inode_id, project);
+        }
 
-                    inode.size = final_size;
-                    inode.update_changes();
+        if name.len() > XATTR_NAME_MAX_LENGTH {
+            return Err(libc::ERANGE);
+        }
+        if value.len() > XATTR_VALUE_MAX_LENGTH {
+            return Err(libc::E2BIG);
+        }
+
+        let (value, access_acl, minimal_acl, size_diff) = {
+            let inode = match self.lookup_node(inode_id) {
+                Ok(inode) => inode,
+                Err(err) => return Err(err),
+            };
+
+            if let Err(err) = VFFS::check_xattr_access(uid, gids, inode, name, true) {
+                return Err(err);
+            }
+
+            let old_size = match inode.xattrs.get(name) {
+                Some(old_value) => {
+                    if flags & libc::XATTR_CREATE != 0 {
+                        return Err(libc::EEXIST);
+                    }
+                    Inode::xattr_size(name, old_value) as i64
+                }
+                None => {
+                    if flags & libc::XATTR_REPLACE != 0 {
+                        return Err(libc::ENODATA);
+                    }
+                    0
+                }
+            };
+
+            let mut value = value.to_vec();
+            let mut access_acl = None;
+            if name == ACCESS_ACL_XATTR || name == DEFAULT_ACL_XATTR {
+                let acl = match Acl::parse(&value) {
+                    Ok(acl) => acl,
+                    Err(err) => return Err(err),
+                };
+                value = acl.to_bytes();
+                if name == ACCESS_ACL_XATTR {
+                    access_acl = Some(acl);
                 }
-                _ => return Err(libc::EISDIR),
             }
+
+            let minimal_acl = access_acl.as_ref().is_some_and(Acl::is_minimal);
+
+            // A minimal access ACL is not stored, so it only releases the attribute it replaces
+            let size_diff = if minimal_acl {
+                -old_size
+            } else {
+                Inode::xattr_size(name, &value) as i64 - old_size
+            };
+
+            (value, access_acl, minimal_acl, size_diff)
+        };
+
+        if let Err(err) = self.check_metadata_growth(&[(inode_id, size_diff)], None) {
+            return Err(err);
         }
 
-        let new_total_size = (self.size as i64 + size_diff) as u64;
+        {
+            let inode = match self.lookup_node_mut(inode_id) {
+                Ok(inode) => inode,
+                Err(err) => return Err(err),
+            };
 
-        if new_total_size > get_max_memory() {
-            return Err(libc::ENOMEM);
+            // The permission bits only follow the access ACL once it is known to fit
+            if let Some(acl) = &access_acl {
+                inode.mode = (inode.mode & !0o777) | acl.mode_bits();
+            }
+            if minimal_acl {
+                inode.xattrs.remove(name);
+            } else {
+                inode.xattrs.insert(name.to_vec(), value);
+            }
+            inode.metadata_change_at = time_now();
         }
 
-        self.size = new_total_size;
+        self.account_metadata(inode_id);
 
         Ok(())
     }
 
+    /// Remove an extended attribute of an inode, releasing its bytes.
+    fn remove_xattr(
+        &mut self,
+        uid: u32,
+        gids: &[u32],
+        inode_id: u64,
+        name: &[u8],
+    ) -> Result<(), c_int> {
+        // The quota report is computed on demand and cannot be changed
+        if name == QUOTA_XATTR {
+            return Err(libc::EPERM);
+        }
+
+        // Removing the project quota id takes the inode out of its project
+        if name == PROJECT_XATTR {
+            if uid != 0 {
+                return Err(libc::EPERM);
+            }
+            return self.set_project(inode_id, 0);
+        }
+
+        {
+            let inode = match self.lookup_node_mut(inode_id) {
+                Ok(inode) => inode,
+                Err(err) => return Err(err),
+            };
+
+            if let Err(err) = VFFS::check_xattr_access(uid, gids, inode, name, true) {
+                return Err(err);
+            }
+
+            if inode.xattrs.remove(name).is_none() {
+                return Err(libc::ENODATA);
+            }
+            inode.metadata_change_at = time_now();
+        }
+
+        self.account_metadata(inode_id);
+
+        Ok(())
+    }
+
+    /// Check whether a user is granted every permission in `mask` on an inode.
+    /// The access ACL of the inode is used when present, and its permission bits otherwise.
+    /// Root is granted everything, except executing a file without any execute bit.
+    fn check_permission(inode: &Inode, uid: u32, gids: &[u32], mask: i32) -> bool {
+        let mask = (mask & 0o7) as u16;
+
+        if uid == 0 {
+            return mask & libc::X_OK as u16 == 0
+                || inode.is_directory()
+                || inode.mode & 0o111 != 0;
+        }
+
+        let acl = match inode.xattrs.get(ACCESS_ACL_XATTR) {
+            Some(bytes) => Acl::parse(bytes).unwrap_or_else(|_| Acl::from_mode(inode.mode)),
+            None => Acl::from_mode(inode.mode),
+        };
+        acl.allows(inode.uid, inode.gid, uid, gids, mask)
+    }
+
+    /// Group IDs of the requesting process, both its effective and supplementary groups.
+    fn request_groups(req: &Request<'_>) -> Vec<u32> {
+        let mut groups = supplementary_groups(req.pid());
+        groups.push(req.gid());
+        groups
+    }
+
+    /// Group IDs of the requesting process needed to check its permissions on an inode.
+    /// The supplementary groups of the requesting process are only read when the
+    /// decision depends on them, that is when the user is neither root nor the owner.
+    fn request_groups_for(req: &Request<'_>, inode: &Inode) -> Vec<u32> {
+        if req.uid() == 0 || req.uid() == inode.uid {
+            vec![req.gid()]
+        } else {
+            VFFS::request_groups(req)
+        }
+    }
+
+    /// Check that the requesting user is granted every permission in `mask` on an inode.
+    fn check_access(&self, req: &Request<'_>, inode_id: u64, mask: i32) -> Result<(), c_int> {
+        let inode = match self.lookup_node(inode_id) {
+            Ok(inode) => inode,
+            Err(err) => return Err(err),
+        };
+
+        let groups = VFFS::request_groups_for(req, inode);
+        if VFFS::check_permission(inode, req.uid(), &groups, mask) {
+            Ok(())
+        } else {
+            Err(libc::EACCES)
+        }
+    }
+
+    /// Check that the requesting user may apply a `setattr` change to an inode.
+    /// Only the owner or root may change the mode, only root may change the owner,
+    /// and the owner may only change the group to one they belong to.
+    /// Setting timestamps to given values, the change and creation times included,
+    /// requires owning the inode, while setting the access and modification times
+    /// to the current time is also allowed with write permission.
+    /// Changing the size requires write permission, unless it goes through a file
+    /// handle that was already opened for writing.
+    fn check_attr_change(
+        &self,
+        req: &Request<'_>,
+        inode_id: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        crtime: Option<SystemTime>,
+        fh: Option<u64>,
+    ) -> Result<(), c_int> {
+        let inode = match self.lookup_node(inode_id) {
+            Ok(inode) => inode,
+            Err(err) => return Err(err),
+        };
+
+        let is_root = req.uid() == 0;
+        let is_owner = req.uid() == inode.uid;
+
+        if mode.is_some() && !is_root && !is_owner {
+            return Err(libc::EPERM);
+        }
+
+        if let Some(new_uid) = uid {
+            if new_uid != inode.uid && !is_root {
+                return Err(libc::EPERM);
+            }
+        }
+
+        if let Some(new_gid) = gid {
+            if new_gid != inode.gid
+                && !is_root
+                && (!is_owner || !VFFS::request_groups(req).contains(&new_gid))
+            {
+                return Err(libc::EPERM);
+            }
+        }
+
+        let specific_time = matches!(atime, Some(TimeOrNow::SpecificTime(_)))
+            || matches!(mtime, Some(TimeOrNow::SpecificTime(_)))
+            || ctime.is_some()
+            || crtime.is_some();
+        if specific_time && !is_root && !is_owner {
+            return Err(libc::EPERM);
+        }
+        if (atime.is_some() || mtime.is_some()) && !is_root && !is_owner {
+            if let Err(err) = self.check_access(req, inode_id, libc::W_OK) {
+                return Err(err);
+            }
+        }
+
+        if size.is_some() && fh.is_none() {
+            return self.check_access(req, inode_id, libc::W_OK);
+        }
+
+        Ok(())
+    }
+
+    /// Compute the mode and extended attributes of an entry created under `parent`.
+    /// When the parent has a default ACL, the entry inherits it as its access ACL,
+    /// limited by the requested `mode` instead of the `umask`. New directories also
+    /// inherit it as their own default ACL.
+    fn inherited_permissions(
+        &self,
+        parent: u64,
+        mode: u32,
+        umask: u32,
+        is_directory: bool,
+    ) -> (u16, BTreeMap<Vec<u8>, Vec<u8>>) {
+        let mut xattrs = BTreeMap::default();
+
+        let default_acl = match self.lookup_node(parent) {
+            Ok(parent_inode) => parent_inode
+                .xattrs
+                .get(DEFAULT_ACL_XATTR)
+                .and_then(|bytes| Acl::parse(bytes).ok()),
+            Err(_) => None,
+        };
+
+        match default_acl {
+            Some(default_acl) => {
+                let access_acl = default_acl.inherit((mode & 0o777) as u16);
+                let new_mode = (mode & !0o777) as u16 | access_acl.mode_bits();
+
+                if !access_acl.is_minimal() {
+                    xattrs.insert(ACCESS_ACL_XATTR.to_vec(), access_acl.to_bytes());
+                }
+                if is_directory {
+                    xattrs.insert(DEFAULT_ACL_XATTR.to_vec(), default_acl.to_bytes());
+                }
+                (new_mode, xattrs)
+            }
+            None => ((mode & !umask) as u16, xattrs),
+        }
+    }
+
+    /// Compute the owning group and mode of an entry created under `parent`.
+    /// Entries created in a setgid directory take the group of the directory, and new
+    /// directories also keep the setgid bit so the behaviour propagates down the tree.
+    /// Other entries lose their setgid bit when the user does not belong to that group.
+    fn inherited_ownership(
+        &self,
+        req: &Request<'_>,
+        parent: u64,
+        mode: u16,
+        is_directory: bool,
+    ) -> (u32, u16) {
+        let setgid = libc::S_ISGID as u16;
+        let parent_gid = match self.lookup_node(parent) {
+            Ok(parent_inode) if parent_inode.mode & setgid != 0 => parent_inode.gid,
+            _ => return (req.gid(), mode),
+        };
+
+        if is_directory {
+            return (parent_gid, mode | setgid);
+        }
+
+        let group_executable =
+            mode & (setgid | libc::S_IXGRP as u16) == setgid | libc::S_IXGRP as u16;
+        if group_executable && req.uid() != 0 && !VFFS::request_groups(req).contains(&parent_gid) {
+            return (parent_gid, mode & !setgid);
+        }
+        (parent_gid, mode)
+    }
+
+    /// Check that the requesting user may remove or rename an entry of a directory.
+    /// In a directory with the sticky bit, only the owner of the entry, the owner
+    /// of the directory or root may do so.
+    fn check_sticky(&self, req: &Request<'_>, parent: u64, inode_id: u64) -> Result<(), c_int> {
+        let parent_inode = match self.lookup_node(parent) {
+            Ok(inode) => inode,
+            Err(err) => return Err(err),
+        };
+        if parent_inode.mode & libc::S_ISVTX as u16 == 0 {
+            return Ok(());
+        }
+
+        let inode = match self.lookup_node(inode_id) {
+            Ok(inode) => inode,
+            Err(err) => return Err(err),
+        };
+        if req.uid() == 0 || req.uid() == parent_inode.uid || req.uid() == inode.uid {
+            Ok(())
+        } else {
+            Err(libc::EPERM)
+        }
+    }
+
+    /// Swap two directory entries, each given as its parent, name and inode.
+    /// Each inode takes the name and parent of the other, and directories carry
+    /// their ".." link along when the parents differ.
+    fn exchange_entries(&mut self, first: (u64, &str, u64), second: (u64, &str, u64)) {
+        let (first_parent, first_name, first_id) = first;
+        let (second_parent, second_name, second_id) = second;
+
+        let mut removed = Vec::new();
+        for (parent, name) in [(first_parent, first_name), (second_parent, second_name)] {
+            if let Ok(parent_inode) = self.lookup_node_mut(parent) {
+                if let InodeData::Directory(dir) = &mut parent_inode.data {
+                    removed.extend(dir.remove_node_by_name(name));
+                }
+                parent_inode.update_changes();
+            }
+        }
+        let file_type_of = |id: u64| removed.iter().find(|node| node.0 == id).unwrap().2;
+        let first_type = file_type_of(first_id);
+        let second_type = file_type_of(second_id);
+
+        for (parent, name, id, file_type, old_parent) in [
+            (
+                first_parent,
+                first_name,
+                second_id,
+                second_type,
+                second_parent,
+            ),
+            (
+                second_parent,
+                second_name,
+                first_id,
+                first_type,
+                first_parent,
+            ),
+        ] {
+            if let Ok(parent_inode) = self.lookup_node_mut(parent) {
+                if let InodeData::Directory(dir) = &mut parent_inode.data {
+                    dir.add_node((id, name.to_string(), file_type));
+                }
+                if file_type == FileType::Directory && parent != old_parent {
+                    parent_inode.hardlinks += 1;
+                }
+            }
+            if file_type == FileType::Directory && parent != old_parent {
+                if let Ok(old_parent_inode) = self.lookup_node_mut(old_parent) {
+                    old_parent_inode.hardlinks -= 1;
+                }
+            }
+            if let Ok(inode) = self.lookup_node_mut(id) {
+                inode.metadata_change_at = time_now();
+                inode.set_name(name.to_string());
+                if let InodeData::Directory(dir) = &mut inode.data {
+                    dir.parent = parent;
+                }
+            }
+        }
+
+        for changed_id in [first_parent, second_parent, first_id, second_id] {
+            self.account_metadata(changed_id);
+        }
+    }
+
+    /// Build the inode of a whiteout entry of `parent`, that is a character device with
+    /// device number 0/0 used by overlay filesystems to hide lower entries.
+    fn whiteout_inode(&self, req: &Request<'_>, parent: u64, name: String) -> Inode {
+        let data = InodeData::CharDevice(SpecialFile::new(name, 0));
+        self.new_entry_inode(req, parent, data, libc::S_IFCHR, 0)
+    }
+
+    /// Whether `ancestor` is the directory `inode_id` itself or one of the directories above it.
+    fn is_ancestor(&self, ancestor: u64, inode_id: u64) -> bool {
+        let mut current = inode_id;
+        loop {
+            if current == ancestor {
+                return true;
+            }
+
+            let parent = match self.lookup_node(current) {
+                Ok(inode) => match &inode.data {
+                    InodeData::Directory(dir) => dir.parent,
+                    _ => return false,
+                },
+                Err(_) => return false,
+            };
+            if parent == current {
+                return false;
+            }
+            current = parent;
+        }
+    }
+
+    /// Build the inode of an entry created under `parent` by the requesting user, with
+    /// the mode, group, extended attributes and project inherited from the parent.
+    /// Symbolic links always grant every permission and never inherit an ACL.
+    fn new_entry_inode(
+        &self,
+        req: &Request<'_>,
+        parent: u64,
+        data: InodeData,
+        mode: u32,
+        umask: u32,
+    ) -> Inode {
+        let is_directory = matches!(data, InodeData::Directory(_));
+        let (inherited_mode, inherited_xattrs) = match data {
+            InodeData::Symlink(_) => (0o777, BTreeMap::default()),
+            _ => self.inherited_permissions(parent, mode, umask, is_directory),
+        };
+        let (gid, inherited_mode) =
+            self.inherited_ownership(req, parent, inherited_mode, is_directory);
+
+        let mut inode = Inode::with_data(
+            get_next_serial_number(),
+            data,
+            inherited_mode,
+            req.uid(),
+            gid,
+        );
+        inode.xattrs = inherited_xattrs;
+        inode.project = self.inherited_project(parent);
+        inode
+    }
+
+    /// Check that the requesting user may add an entry named `name` to `parent`,
+    /// and build its inode with `new_entry_inode`.
+    /// The parent must be a directory without an entry with the same name, and the
+    /// new inode must fit under the inode limit, the memory limit and the quotas.
+    fn prepare_new_entry(
+        &self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &str,
+        data: InodeData,
+        mode: u32,
+        umask: u32,
+    ) -> Result<Inode, c_int> {
+        // Adding an entry requires write and search permission on the parent directory
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            return Err(err);
+        }
+
+        match self.lookup_node(parent) {
+            Ok(parent_inode) => match &parent_inode.data {
+                InodeData::Directory(dir) => {
+                    if dir.find_node_by_name(name).is_some() {
+                        return Err(libc::EEXIST);
+                    }
+                }
+                _ => return Err(libc::ENOTDIR),
+            },
+            Err(err) => return Err(err),
+        }
+
+        if let Err(err) = self.check_inode_limit() {
+            return Err(err);
+        }
+
+        let inode = self.new_entry_inode(req, parent, data, mode, umask);
+        if let Err(err) = self.check_new_inode(parent, &inode, name) {
+            return Err(err);
+        }
+        Ok(inode)
+    }
+
+    /// Add a new inode to the filesystem and link it to `parent` under its name,
+    /// counting the ".." link of a new directory in the parent.
+    /// Returns the attributes of the new inode.
+    fn add_new_entry(&mut self, parent: u64, inode: Inode) -> FileAttr {
+        let entry = (
+            inode.id,
+            inode.get_name().clone(),
+            FileType::from(&inode.data),
+        );
+        let attr: FileAttr = (&inode).into();
+
+        self.append_inode(inode);
+
+        if let Ok(parent_inode) = self.lookup_node_mut(parent) {
+            if entry.2 == FileType::Directory {
+                parent_inode.hardlinks += 1;
+            }
+            parent_inode.append_file_to_directory(entry);
+            parent_inode.update_changes();
+        }
+        self.account_metadata(parent);
+
+        attr
+    }
+
+    /// Check that one more inode fits under the inode limit.
+    fn check_inode_limit(&self) -> Result<(), c_int> {
+        if self.inodes.len() as u64 >= get_max_inodes() {
+            Err(libc::ENOSPC)
+        } else {
+            Ok(())
+        }
+    }
+
     fn validate_and_return_node_name(name: &OsStr) -> Result<String, c_int> {
         let name_str = name.to_str().unwrap();
         if name_str.len() > MAX_NODE_NAME_LENGTH {
@@ -193,11 +1329,7 @@ impl VFFS {
 
         match self.inodes.get(&root_id) {
             Some(inode) => {
-                let root_name = match &inode.data {
-                    InodeData::Directory(dir) => &dir.name,
-                    InodeData::File(f) => &f.name,
-                };
-                println!("{}", root_name);
+                println!("{}", inode.get_name());
 
                 self.print_recursive(root_id, "".to_string());
             }
@@ -213,7 +1345,7 @@ impl VFFS {
         };
 
         if let InodeData::Directory(directory) = &inode.data {
-            let mut children = directory.nodes.clone();
+            let mut children: Vec<_> = directory.nodes().cloned().collect();
             children.sort_by(|a, b| a.1.cmp(&b.1));
 
             let count = children.len();
@@ -238,6 +1370,22 @@ impl VFFS {
 }
 
 impl Filesystem for VFFS {
+    /// Initialize the filesystem, negotiating the capabilities it relies on with the kernel.
+    /// The umask is applied by the filesystem itself, so it can be replaced by
+    /// inherited default ACLs.
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        if let Err(unsupported) = config.add_capabilities(consts::FUSE_DONT_MASK) {
+            debug!("Kernel does not support capabilities {unsupported:#x}");
+        }
+        // Let the kernel fetch entry attributes along with directory listings
+        if let Err(unsupported) =
+            config.add_capabilities(consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO)
+        {
+            debug!("Kernel does not support capabilities {unsupported:#x}");
+        }
+        Ok(())
+    }
+
     /// Create a new file in the specified parent directory.
     /// The creation of the file consists of allocating a new inode, adding it to the VFFS
     /// and updating the parent directory structure to include the new file.
@@ -246,7 +1394,7 @@ impl Filesystem for VFFS {
     /// `name` is the name of the new file to be created
     fn create(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         mode: u32,
@@ -265,50 +1413,20 @@ impl Filesystem for VFFS {
             }
         };
 
-        // Check if parent is a directory
-        // It must be in a local scope to avoid holding the borrow too long
-        {
-            let parent_inode = match self.lookup_node(parent) {
-                Ok(inode) => inode,
-                Err(err) => {
-                    reply.error(err);
-                    return;
-                }
-            };
-
-            if !parent_inode.is_directory() {
-                reply.error(libc::ENOTDIR);
+        let data = InodeData::File(File::new(name_str.clone()));
+        let new_entry = self.prepare_new_entry(req, parent, &name_str, data, mode, umask);
+        let mut new_inode = match new_entry {
+            Ok(inode) => inode,
+            Err(err) => {
+                reply.error(err);
                 return;
             }
-        }
-
-        let new_inode = Inode {
-            id: get_next_serial_number(),
-            size: 0,
-            updated_at: time_now(),
-            accessed_at: time_now(),
-            metadata_change_at: time_now(),
-            data: InodeData::File(File::new(name_str.clone())),
-            mode: (mode & !umask) as u16,
-            hardlinks: 1,
-            uid: _req.uid(),
-            gid: _req.gid(),
-            xattrs: BTreeMap::default(),
         };
-        let new_inode_id = new_inode.id;
-        let file_attr: FileAttr = (&new_inode).into();
+        // The reply both looks up and opens the new file
+        new_inode.lookups = 1;
+        new_inode.open_handles = 1;
 
-        // Add the new inode to the filesystem
-        self.append_inode(new_inode);
-
-        // Add the new file to the parent directory structure
-        if let Ok(parent_inode) = self.lookup_node_mut(parent) {
-            let inode_data = (new_inode_id, name_str, FileType::RegularFile);
-            parent_inode.append_file_to_directory(inode_data);
-        } else {
-            reply.error(libc::ENOENT);
-            return;
-        }
+        let file_attr = self.add_new_entry(parent, new_inode);
 
         // println!(
         //     "Created inode {:?} for create with parent: {parent} and name: {:?}",
@@ -335,6 +1453,52 @@ impl Filesystem for VFFS {
         }
     }
 
+    /// Report the capacity and usage of the filesystem.
+    /// Blocks are derived from the memory limit and the memory in use. Free inodes
+    /// are bounded by the inode limit, and by the memory left since every inode
+    /// takes at least the memory of an empty one.
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let block_size = u64::from(BLOCK_SIZE);
+        let free_memory = get_max_memory().saturating_sub(self.size);
+
+        let blocks = get_max_memory() / block_size;
+        let free_blocks = free_memory / block_size;
+
+        let used_inodes = self.inodes.len() as u64;
+        let free_inodes = (free_memory / size_of::<Inode>() as u64)
+            .min(get_max_inodes().saturating_sub(used_inodes));
+        let total_inodes = used_inodes + free_inodes;
+
+        reply.statfs(
+            blocks,
+            free_blocks,
+            free_blocks,
+            total_inodes,
+            free_inodes,
+            BLOCK_SIZE,
+            MAX_NODE_NAME_LENGTH as u32,
+            BLOCK_SIZE,
+        );
+    }
+
+    /// Forget references to an inode handed to the kernel.
+    /// The `nlookup` parameter is the number of lookups to drop from the inode count,
+    /// after which an inode that was already removed from every directory is reclaimed.
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        if let Ok(inode) = self.lookup_node_mut(ino) {
+            inode.lookups = inode.lookups.saturating_sub(nlookup);
+        }
+
+        self.reclaim_if_unused(ino);
+    }
+
+    /// Forget references to multiple inodes at once.
+    fn batch_forget(&mut self, req: &Request<'_>, nodes: &[fuse_forget_one]) {
+        for node in nodes {
+            self.forget(req, node.nodeid, node.nlookup);
+        }
+    }
+
     /// Look up a directory entry by name and get its attributes.
     /// The method searches for any entry with the given name in the specified parent directory
     /// structure. If found, it retrieves the corresponding inode from the VFFS and returns its
@@ -342,11 +1506,17 @@ impl Filesystem for VFFS {
     ///
     /// The `parent` parameter is the inode number of the parent directory,
     /// and `name` is the name of the entry to look up.
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let name_str = name.to_str().unwrap();
         // println!("lookup parent: {parent}, name: {name_str}. Looking for inode...");
 
-        match self.lookup_node(parent) {
+        // Looking up an entry requires search permission on the parent directory
+        if let Err(err) = self.check_access(req, parent, libc::X_OK) {
+            reply.error(err);
+            return;
+        }
+
+        let file_inode_id = match self.lookup_node(parent) {
             Ok(inode) => {
                 // Check if is Directory
                 {
@@ -374,41 +1544,105 @@ impl Filesystem for VFFS {
                     }
                 };
 
-                // Find the inode for the file
-                let file_inode = match self.lookup_node(file_entry.0) {
-                    Ok(inode) => inode,
-                    Err(err) => {
-                        reply.error(err);
-                        return;
-                    }
-                };
+                file_entry.0
+            }
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+
+        // Find the inode for the file, counting the reference handed to the kernel
+        let file_inode = match self.lookup_node_mut(file_inode_id) {
+            Ok(inode) => inode,
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+        file_inode.lookups += 1;
+
+        // println!(
+        //     "Found inode {:?} for lookup with parent: {parent} and name: {name_str}",
+        //     file_inode
+        // );
+        reply.entry(&Duration::new(0, 0), &(&*file_inode).into(), 0)
+    }
+
+    /// Create a symbolic link in the specified parent directory.
+    /// The link target is stored in the new inode as raw bytes, and its
+    /// length is both the size of the link and the memory it is charged.
+    ///
+    /// The `parent` parameter is the inode number of the parent directory,
+    /// `link_name` is the name of the new link and `target` is the path it points to.
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        let name_str = match VFFS::validate_and_return_node_name(link_name) {
+            Ok(name) => name,
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+
+        let target_bytes = target.as_os_str().as_bytes().to_vec();
+        if target_bytes.len() > libc::PATH_MAX as usize {
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
 
-                // println!(
-                //     "Found inode {:?} for lookup with parent: {parent} and name: {name_str}",
-                //     file_inode
-                // );
-                reply.entry(&Duration::new(0, 0), &file_inode.into(), 0)
+        let data = InodeData::Symlink(Symlink::new(name_str.clone(), target_bytes));
+        let new_entry = self.prepare_new_entry(req, parent, &name_str, data, 0o777, 0);
+        let mut new_inode = match new_entry {
+            Ok(inode) => inode,
+            Err(err) => {
+                reply.error(err);
+                return;
             }
+        };
+        new_inode.lookups = 1;
+
+        let attr_reply = self.add_new_entry(parent, new_inode);
+
+        reply.entry(&Duration::new(0, 0), &attr_reply, 0);
+    }
+
+    /// Read the target of a symbolic link.
+    ///
+    /// The `ino` parameter is the inode number of the link.
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.lookup_node(ino) {
+            Ok(inode) => match &inode.data {
+                InodeData::Symlink(symlink) => reply.data(&symlink.target),
+                _ => reply.error(libc::EINVAL),
+            },
             Err(err) => reply.error(err),
         }
     }
 
-    /// Create a new directory in the specified parent directory.
-    /// The creation of the directory consists of allocating a new inode,
-    /// adding it to the VFFS, and updating the parent directory structure
-    fn mkdir(
+    /// Create a file node in the specified parent directory.
+    /// Besides regular files, the node can be a FIFO, a Unix socket or a
+    /// character or block device, as selected by the file type bits of `mode`.
+    ///
+    /// The `parent` parameter is the inode number of the parent directory,
+    /// `name` is the name of the new node and `rdev` is the device number
+    /// used by device nodes.
+    fn mknod(
         &mut self,
         req: &Request<'_>,
         parent: u64,
         name: &OsStr,
-        mut mode: u32,
+        mode: u32,
         umask: u32,
+        rdev: u32,
         reply: ReplyEntry,
     ) {
-        // println!(
-        //     "mkdir() called with {parent:?} {name:?} {mode:o}"
-        // );
-
         let name_str = match VFFS::validate_and_return_node_name(name) {
             Ok(name) => name,
             Err(err) => {
@@ -417,65 +1651,69 @@ impl Filesystem for VFFS {
             }
         };
 
-        // Check if parent is a directory
-        {
-            if let Ok(parent_inode) = self.lookup_node(parent) {
-                if let InodeData::Directory(dir) = &parent_inode.data {
-                    if dir.find_node_by_name(&name_str).is_some() {
-                        reply.error(libc::EEXIST);
-                        return;
-                    }
-                } else {
-                    reply.error(libc::ENOTDIR);
-                    return;
-                }
-            } else {
-                reply.error(libc::ENOENT);
+        let data = match mode & libc::S_IFMT {
+            libc::S_IFREG => InodeData::File(File::new(name_str.clone())),
+            libc::S_IFIFO => InodeData::Fifo(SpecialFile::new(name_str.clone(), 0)),
+            libc::S_IFSOCK => InodeData::Socket(SpecialFile::new(name_str.clone(), 0)),
+            libc::S_IFCHR => InodeData::CharDevice(SpecialFile::new(name_str.clone(), rdev)),
+            libc::S_IFBLK => InodeData::BlockDevice(SpecialFile::new(name_str.clone(), rdev)),
+            _ => {
+                reply.error(libc::EINVAL);
                 return;
             }
-        }
+        };
 
-        // Update parent metadata
-        match self.lookup_node_mut(parent) {
-            Ok(parent_inode) => {
-                parent_inode.update_changes();
-            }
+        let new_entry = self.prepare_new_entry(req, parent, &name_str, data, mode, umask);
+        let mut new_inode = match new_entry {
+            Ok(inode) => inode,
             Err(err) => {
                 reply.error(err);
                 return;
             }
         };
+        new_inode.lookups = 1;
 
-        let new_inode = Inode {
-            id: get_next_serial_number(),
-            size: 0,
-            updated_at: time_now(),
-            accessed_at: time_now(),
-            metadata_change_at: time_now(),
-            data: InodeData::Directory(Directory::new(name_str.clone())),
-            mode: (mode & !umask) as u16,
-            hardlinks: 1,
-            uid: req.uid(),
-            gid: req.gid(),
-            xattrs: BTreeMap::default(),
-        };
+        let attr_reply = self.add_new_entry(parent, new_inode);
 
-        let new_inode_id = new_inode.id;
-        let attr_reply = (&new_inode).into();
+        reply.entry(&Duration::new(0, 0), &attr_reply, 0);
+    }
 
-        self.append_inode(new_inode);
+    /// Create a new directory in the specified parent directory.
+    /// The creation of the directory consists of allocating a new inode,
+    /// adding it to the VFFS, and updating the parent directory structure
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mut mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        // println!(
+        //     "mkdir() called with {parent:?} {name:?} {mode:o}"
+        // );
 
-        // Link directory to parent
-        match self.lookup_node_mut(parent) {
-            Ok(parent_inode) => {
-                let entry = (new_inode_id, name_str.clone(), FileType::Directory);
-                parent_inode.append_file_to_directory(entry);
+        let name_str = match VFFS::validate_and_return_node_name(name) {
+            Ok(name) => name,
+            Err(err) => {
+                reply.error(err);
+                return;
             }
+        };
+
+        let data = InodeData::Directory(Directory::new(name_str.clone(), parent));
+        let new_entry = self.prepare_new_entry(req, parent, &name_str, data, mode, umask);
+        let mut new_inode = match new_entry {
+            Ok(inode) => inode,
             Err(err) => {
                 reply.error(err);
                 return;
             }
-        }
+        };
+        new_inode.lookups = 1;
+
+        let attr_reply = self.add_new_entry(parent, new_inode);
 
         // println!(
         //     "Created inode {:?} for mkdir with parent: {parent} and name: {:?}",
@@ -491,7 +1729,7 @@ impl Filesystem for VFFS {
     fn open(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
         // debug!("open() function called for {inode:?}");
 
-        let (_, _, _) = match flags & libc::O_ACCMODE {
+        let (access_mask, _, _) = match flags & libc::O_ACCMODE {
             libc::O_RDONLY => {
                 if flags & libc::O_TRUNC != 0 {
                     reply.error(libc::EACCES);
@@ -512,10 +1750,54 @@ impl Filesystem for VFFS {
             }
         };
 
+        if let Err(err) = self.check_access(req, inode, access_mask) {
+            reply.error(err);
+            return;
+        }
+
+        match self.lookup_node_mut(inode) {
+            Ok(node) => node.open_handles += 1,
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        }
+
         let fh = inode;
         reply.opened(fh, 0);
     }
 
+    /// Check file access permissions for the `access(2)` system call.
+    /// The `mask` holds the `R_OK`, `W_OK` and `X_OK` bits to check,
+    /// or is `F_OK` to only check that the inode exists.
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        match self.check_access(req, ino, mask) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    /// Release an open file handle.
+    /// A file removed from every directory while open is reclaimed
+    /// once its last handle is released and the kernel forgot it.
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        if let Ok(inode) = self.lookup_node_mut(ino) {
+            inode.open_handles = inode.open_handles.saturating_sub(1);
+        }
+
+        self.reclaim_if_unused(ino);
+        reply.ok();
+    }
+
     fn read(
         &mut self,
         _req: &Request,
@@ -533,22 +1815,24 @@ impl Filesystem for VFFS {
         match self.lookup_node(inode) {
             Ok(node) => match &node.data {
                 InodeData::File(virtual_file) => {
-                    let data_bytes = virtual_file.data.as_bytes();
-                    let offset = offset as usize;
+                    let offset = offset as u64;
 
-                    if offset >= data_bytes.len() {
+                    if offset >= node.size {
                         reply.data(&[]);
                         return;
                     }
 
-                    let available = data_bytes.len() - offset;
-                    let to_read = std::cmp::min(size as usize, available);
+                    let available = node.size - offset;
+                    let to_read = std::cmp::min(size as u64, available);
 
-                    reply.data(&data_bytes[offset..offset + to_read]);
+                    reply.data(&virtual_file.read_at(offset, to_read));
                 }
                 InodeData::Directory(_) => {
                     reply.error(libc::EISDIR);
                 }
+                _ => {
+                    reply.error(libc::EINVAL);
+                }
             },
             Err(error_code) => {
                 reply.error(error_code);
@@ -563,28 +1847,30 @@ impl Filesystem for VFFS {
     /// The `ino` parameter is the inode number of the directory to read.
     fn readdir(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
         // println!("readdir() called with ino: {ino}, fh: {fh}, offset: {offset}");
+        // Listing a directory requires read permission on it
+        if let Err(err) = self.check_access(req, ino, libc::R_OK) {
+            reply.error(err);
+            return;
+        }
+
         match self.lookup_node(ino) {
             Ok(inode) => {
                 // println!("Found inode for readdir: {:?}", inode);
                 match &inode.data {
                     InodeData::Directory(directory) => {
-                        let mut entry_offset: i64 = 0;
-                        for (id, name, filetype) in &directory.nodes {
-                            if entry_offset >= offset {
-                                let buffer_full = reply.add(*id, entry_offset + 1, *filetype, name);
-
-                                if buffer_full {
-                                    break;
-                                }
+                        for (cookie, id, name, filetype) in directory.entries_after(ino, offset) {
+                            let buffer_full = reply.add(id, cookie as i64, filetype, name);
+
+                            if buffer_full {
+                                break;
                             }
-                            entry_offset += 1;
                         }
                         reply.ok();
                     }
@@ -597,19 +1883,99 @@ impl Filesystem for VFFS {
         }
     }
 
+    /// Read the entries of a directory along with their attributes, sparing the
+    /// kernel a `lookup` for each of them.
+    ///
+    /// Every entry other than "." and ".." counts as a lookup, released later by `forget`.
+    fn readdirplus(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        // Listing a directory requires read permission on it
+        if let Err(err) = self.check_access(req, ino, libc::R_OK) {
+            reply.error(err);
+            return;
+        }
+
+        let directory = match self.lookup_node(ino) {
+            Ok(inode) => match &inode.data {
+                InodeData::Directory(directory) => directory,
+                _ => {
+                    reply.error(libc::ENOTDIR);
+                    return;
+                }
+            },
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+
+        let mut listed = Vec::new();
+        for (cookie, id, name, _) in directory.entries_after(ino, offset) {
+            let attr: FileAttr = match self.inodes.get(&id) {
+                Some(inode) => inode.into(),
+                None => continue,
+            };
+
+            let buffer_full = reply.add(id, cookie as i64, name, &Duration::new(0, 0), &attr, 0);
+            if buffer_full {
+                break;
+            }
+            if cookie >= FIRST_ENTRY_COOKIE {
+                listed.push(id);
+            }
+        }
+
+        // Count the references handed to the kernel
+        for id in listed {
+            if let Ok(inode) = self.lookup_node_mut(id) {
+                inode.lookups += 1;
+            }
+        }
+        reply.ok();
+    }
+
     /// Rename a file or directory.
     /// This method moves a file or directory from one location to another,
     /// optionally renaming it in the process.
     fn rename(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         new_parent: u64,
         new_name: &OsStr,
-        _flags: u32,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
+        // Reject unknown flags and combinations that contradict each other
+        let noreplace = flags & libc::RENAME_NOREPLACE != 0;
+        let exchange = flags & libc::RENAME_EXCHANGE != 0;
+        let whiteout = flags & libc::RENAME_WHITEOUT != 0;
+        if flags & !(libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE | libc::RENAME_WHITEOUT) != 0
+            || (exchange && (noreplace || whiteout))
+        {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        // Leaving a whiteout behind creates a device node, which is reserved to root
+        if whiteout && req.uid() != 0 {
+            reply.error(libc::EPERM);
+            return;
+        }
+        if whiteout {
+            if let Err(err) = self.check_inode_limit() {
+                reply.error(err);
+                return;
+            }
+        }
+
         let name_str = name.to_str().unwrap().to_string();
         let new_name_string = match VFFS::validate_and_return_node_name(new_name) {
             Ok(name) => name,
@@ -619,6 +1985,14 @@ impl Filesystem for VFFS {
             }
         };
 
+        // Moving an entry requires write and search permission on both parent directories
+        for directory_id in [parent, new_parent] {
+            if let Err(err) = self.check_access(req, directory_id, libc::W_OK | libc::X_OK) {
+                reply.error(err);
+                return;
+            }
+        }
+
         // Find source node in the parent directory
         let source_inode_id = {
             let parent_inode = match self.lookup_node(parent) {
@@ -675,31 +2049,156 @@ impl Filesystem for VFFS {
             }
         };
 
+        match target_inode_id_opt {
+            Some(_) if noreplace => {
+                reply.error(libc::EEXIST);
+                return;
+            }
+            None if exchange => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+            _ => {}
+        }
+
+        // A directory cannot be moved below itself, which would detach its subtree
+        let mut moved = vec![(source_inode_id, new_parent)];
+        if exchange {
+            moved.extend(target_inode_id_opt.map(|target_id| (target_id, parent)));
+        }
+        for (moved_id, destination) in moved {
+            if self.lookup_node(moved_id).unwrap().is_directory()
+                && self.is_ancestor(moved_id, destination)
+            {
+                reply.error(libc::EINVAL);
+                return;
+            }
+
+            // Entries cannot move into another project, which would dodge its quota
+            let destination_project = self.lookup_node(destination).unwrap().project;
+            if destination_project != 0
+                && self.lookup_node(moved_id).unwrap().project != destination_project
+            {
+                reply.error(libc::EXDEV);
+                return;
+            }
+        }
+
+        // Moving a directory to another parent rewrites its ".." entry, which requires write permission
+        let mut moved_ids = vec![source_inode_id];
+        if exchange {
+            moved_ids.extend(target_inode_id_opt);
+        }
+        for moved_id in moved_ids {
+            if parent != new_parent && self.lookup_node(moved_id).unwrap().is_directory() {
+                if let Err(err) = self.check_access(req, moved_id, libc::W_OK) {
+                    reply.error(err);
+                    return;
+                }
+            }
+        }
+
+        // Sticky parents only let the owners of an entry or of the directory move or replace it
+        if let Err(err) = self.check_sticky(req, parent, source_inode_id) {
+            reply.error(err);
+            return;
+        }
+        if let Some(target_id) = target_inode_id_opt {
+            if let Err(err) = self.check_sticky(req, new_parent, target_id) {
+                reply.error(err);
+                return;
+            }
+        }
+
+        // Swap both entries atomically when exchanging them
+        if exchange {
+            let target_id = target_inode_id_opt.unwrap();
+            if target_id != source_inode_id {
+                let growth = VFFS::rename_growth(
+                    (parent, &name_str, source_inode_id),
+                    (new_parent, &new_name_string, Some(target_id)),
+                    true,
+                    false,
+                );
+                if let Err(err) = self.check_metadata_growth(&growth, None) {
+                    reply.error(err);
+                    return;
+                }
+
+                self.exchange_entries(
+                    (parent, &name_str, source_inode_id),
+                    (new_parent, &new_name_string, target_id),
+                );
+            }
+
+            reply.ok();
+            return;
+        }
+
         // Handle target node if it exists
         if let Some(target_id) = target_inode_id_opt {
+            // Both names already link to the same inode, so there is nothing to do
             if target_id == source_inode_id {
                 reply.ok();
                 return;
             }
 
+            let source_is_directory = self.lookup_node(source_inode_id).unwrap().is_directory();
             let target_inode = self.lookup_node(target_id).unwrap();
 
             if let InodeData::Directory(dir) = &target_inode.data {
-                if !dir.nodes.is_empty() {
+                if !source_is_directory {
+                    reply.error(libc::EISDIR);
+                    return;
+                }
+                if !dir.is_empty() {
                     reply.error(libc::ENOTEMPTY);
                     return;
                 }
+            } else if source_is_directory {
+                reply.error(libc::ENOTDIR);
+                return;
             }
         }
 
-        // Remove source from old parent and add to new parent
+        // The renamed entries and the whiteout left in place of the source entry
+        // must fit before anything is moved
+        let whiteout_inode = if whiteout {
+            Some(self.whiteout_inode(req, parent, name_str.clone()))
+        } else {
+            None
+        };
+        let growth = VFFS::rename_growth(
+            (parent, &name_str, source_inode_id),
+            (new_parent, &new_name_string, target_inode_id_opt),
+            false,
+            whiteout,
+        );
+        if let Err(err) = self.check_metadata_growth(&growth, whiteout_inode.as_ref()) {
+            reply.error(err);
+            return;
+        }
+
+        // Remove the replaced target entry from the new parent
         if let Some(target_id) = target_inode_id_opt {
-            self.remove_inode(target_id);
+            let target_is_directory = self.lookup_node(target_id).unwrap().is_directory();
 
             if let Ok(new_p_inode) = self.lookup_node_mut(new_parent) {
                 if let InodeData::Directory(dir) = &mut new_p_inode.data {
-                    dir.nodes.retain(|(id, _, _)| *id != target_id);
+                    dir.remove_node_by_name(&new_name_string);
+                }
+                if target_is_directory {
+                    new_p_inode.hardlinks -= 1;
+                }
+            }
+
+            if target_is_directory {
+                if let Ok(target_inode) = self.lookup_node_mut(target_id) {
+                    target_inode.hardlinks = 0;
                 }
+                self.reclaim_if_unused(target_id);
+            } else {
+                self.release_link(target_id);
             }
         }
 
@@ -712,14 +2211,17 @@ impl Filesystem for VFFS {
             parent_inode.update_changes();
 
             if let InodeData::Directory(dir) = &mut parent_inode.data {
-                let (_, _, f_type) = dir.find_node_by_name(&name_str).unwrap();
+                let (_, _, f_type) = dir.remove_node_by_name(&name_str).unwrap();
                 file_type_cache = f_type;
-
-                dir.nodes.retain(|(id, _, _)| *id != source_inode_id);
             } else {
                 reply.error(libc::EIO);
                 return;
             }
+
+            // A moved directory takes its ".." link along to the new parent
+            if file_type_cache == FileType::Directory {
+                parent_inode.hardlinks -= 1;
+            }
         }
 
         // Add to new parent directory
@@ -729,6 +2231,10 @@ impl Filesystem for VFFS {
                 .expect("New Parent checked in Phase 1");
             new_parent_inode.update_changes();
 
+            if file_type_cache == FileType::Directory {
+                new_parent_inode.hardlinks += 1;
+            }
+
             if let InodeData::Directory(dir) = &mut new_parent_inode.data {
                 dir.add_node((source_inode_id, new_name_string.clone(), file_type_cache));
             }
@@ -740,12 +2246,19 @@ impl Filesystem for VFFS {
                 .lookup_node_mut(source_inode_id)
                 .expect("Source checked in Phase 1");
             inode.metadata_change_at = time_now();
-
-            match &mut inode.data {
-                InodeData::File(f) => f.name = new_name_string,
-                InodeData::Directory(d) => d.name = new_name_string,
+            inode.set_name(new_name_string);
+            if let InodeData::Directory(dir) = &mut inode.data {
+                dir.parent = new_parent;
             }
         }
+        for changed_id in [parent, new_parent, source_inode_id] {
+            self.account_metadata(changed_id);
+        }
+
+        // Leave a whiteout in place of the source entry
+        if let Some(whiteout_inode) = whiteout_inode {
+            self.add_new_entry(parent, whiteout_inode);
+        }
 
         println!("Renamed file/dir. Filesystem:");
         self.tree();
@@ -757,10 +2270,16 @@ impl Filesystem for VFFS {
     /// The method locates the inode corresponding to the directory to be removed,
     /// checks if it is empty, removes the entry from the parent directory structure,
     /// and deletes the inode from the VFFS.
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let name_str = name.to_str().unwrap();
         // println!("rmdir() called with parent: {parent}, name: {name_str}");
 
+        // Removing an entry requires write and search permission on the parent directory
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            reply.error(err);
+            return;
+        }
+
         // Find the inode to be removed matching it as a directory
         let inode_id = {
             let parent_inode = match self.lookup_node(parent) {
@@ -793,11 +2312,17 @@ impl Filesystem for VFFS {
             }
         };
 
+        // A sticky parent only lets the owners of the entry or of the directory remove it
+        if let Err(err) = self.check_sticky(req, parent, inode_id) {
+            reply.error(err);
+            return;
+        }
+
         // Check if the directory is empty
         match self.lookup_node(inode_id) {
             Ok(inode) => {
                 if let InodeData::Directory(dir) = &inode.data {
-                    if !dir.nodes.is_empty() {
+                    if !dir.is_empty() {
                         reply.error(libc::ENOTEMPTY);
                         return;
                     }
@@ -812,12 +2337,13 @@ impl Filesystem for VFFS {
             }
         }
 
-        // Remove the entry from the parent directory
+        // Remove the entry from the parent directory, along with the ".." link it held
         match self.lookup_node_mut(parent) {
             Ok(parent_inode) => {
                 if let InodeData::Directory(dir) = &mut parent_inode.data {
-                    dir.nodes.retain(|(_, n, _)| n != name_str);
+                    dir.remove_node_by_name(name_str);
                 }
+                parent_inode.hardlinks -= 1;
                 parent_inode.update_changes();
             }
             Err(err) => {
@@ -825,9 +2351,14 @@ impl Filesystem for VFFS {
                 return;
             }
         }
+        self.account_metadata(parent);
 
-        // Remove the inode from the filesystem
-        self.remove_inode(inode_id);
+        // Drop the links of the directory, removing it from the filesystem if unused
+        if let Ok(inode) = self.lookup_node_mut(inode_id) {
+            inode.hardlinks = 0;
+            inode.metadata_change_at = time_now();
+        }
+        self.reclaim_if_unused(inode_id);
 
         println!("Removed directory. Filesystem:");
         self.tree();
@@ -838,17 +2369,17 @@ impl Filesystem for VFFS {
     /// The method updates received attributes of the specified inode in the VFFS
     fn setattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<TimeOrNow>,
-        _mtime: Option<TimeOrNow>,
-        _ctime: Option<SystemTime>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
         fh: Option<u64>,
-        _crtime: Option<SystemTime>,
+        crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
         flags: Option<u32>,
@@ -859,11 +2390,47 @@ impl Filesystem for VFFS {
         //     mode, uid, gid, size, fh, flags
         // );
 
+        if let Err(err) = self.check_attr_change(
+            req, ino, mode, uid, gid, size, atime, mtime, ctime, crtime, fh,
+        ) {
+            reply.error(err);
+            return;
+        }
+
+        // Check the quotas of the new owners and resize the file contents before
+        // anything else, so a rejected change leaves the inode untouched.
+        // Resizing never adds to the bytes charged for a file, so they can be checked first.
+        if uid.is_some() || gid.is_some() {
+            if let Err(err) = self.check_quota_transfer(ino, uid, gid) {
+                reply.error(err);
+                return;
+            }
+        }
+        if let Some(new_size) = size {
+            if let Err(err) = self.truncate_file_data(ino, new_size) {
+                reply.error(err);
+                return;
+            }
+        }
+
+        // Move the charged bytes and inode to the quotas of the new owners
+        if uid.is_some() || gid.is_some() {
+            self.transfer_quota(ino, uid, gid);
+        }
+
         // Update the inode attributes in a local scope
         match self.lookup_node_mut(ino) {
             Ok(inode) => {
                 if let Some(new_mode) = mode {
                     inode.mode = new_mode as u16;
+
+                    // Keep the access ACL in sync with the new permission bits
+                    if let Some(bytes) = inode.xattrs.get_mut(ACCESS_ACL_XATTR) {
+                        if let Ok(mut acl) = Acl::parse(bytes) {
+                            acl.apply_mode(inode.mode);
+                            *bytes = acl.to_bytes();
+                        }
+                    }
                 }
                 if let Some(new_uid) = uid {
                     inode.uid = new_uid;
@@ -871,10 +2438,11 @@ impl Filesystem for VFFS {
                 if let Some(new_gid) = gid {
                     inode.gid = new_gid;
                 }
-                if let Some(new_size) = size {
-                    inode.size = new_size;
+                // Changing the owner of a file drops its setuid and setgid bits, even for root
+                if uid.is_some() || gid.is_some() {
+                    inode.clear_setid_bits();
                 }
-                if let Some(access_time) = _atime {
+                if let Some(access_time) = atime {
                     match access_time {
                         TimeOrNow::SpecificTime(system_time) => {
                             inode.accessed_at = time_from_system_time(&system_time);
@@ -884,7 +2452,24 @@ impl Filesystem for VFFS {
                         }
                     }
                 }
-                inode.update_changes();
+                if let Some(modification_time) = mtime {
+                    match modification_time {
+                        TimeOrNow::SpecificTime(system_time) => {
+                            inode.updated_at = time_from_system_time(&system_time);
+                        }
+                        TimeOrNow::Now => {
+                            inode.updated_at = time_now();
+                        }
+                    }
+                }
+                if let Some(creation_time) = crtime {
+                    inode.created_at = time_from_system_time(&creation_time);
+                }
+                // Any attribute change is a status change, unless the kernel provides its time
+                inode.metadata_change_at = match ctime {
+                    Some(change_time) => time_from_system_time(&change_time),
+                    None => time_now(),
+                };
             }
             Err(err) => {
                 reply.error(err);
@@ -907,11 +2492,17 @@ impl Filesystem for VFFS {
     /// Removes a file from the filesystem.
     /// The method locates the inode corresponding to the file to be removed,
     /// removes the entry from the parent directory structure,
-    /// and deletes the inode from the VFFS.
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    /// and deletes the inode from the VFFS once its last hard link is gone.
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let name_str = name.to_str().unwrap();
         // println!("unlink() called with parent: {parent}, name: {name_str}");
 
+        // Removing an entry requires write and search permission on the parent directory
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            reply.error(err);
+            return;
+        }
+
         // Find the inode to be unlinked
         let inode_id = {
             let parent_inode = match self.lookup_node(parent) {
@@ -936,6 +2527,10 @@ impl Filesystem for VFFS {
             };
 
             match directory.find_node_by_name(name_str) {
+                Some((_, _, FileType::Directory)) => {
+                    reply.error(libc::EISDIR);
+                    return;
+                }
                 Some((id, _, _)) => id,
                 None => {
                     reply.error(libc::ENOENT);
@@ -944,11 +2539,17 @@ impl Filesystem for VFFS {
             }
         };
 
+        // A sticky parent only lets the owners of the entry or of the directory remove it
+        if let Err(err) = self.check_sticky(req, parent, inode_id) {
+            reply.error(err);
+            return;
+        }
+
         // Remove the entry from the parent directory
         match self.lookup_node_mut(parent) {
             Ok(parent_inode) => {
                 if let InodeData::Directory(dir) = &mut parent_inode.data {
-                    dir.nodes.retain(|(_, n, _)| n != name_str);
+                    dir.remove_node_by_name(name_str);
                 }
                 parent_inode.update_changes();
             }
@@ -957,21 +2558,118 @@ impl Filesystem for VFFS {
                 return;
             }
         }
+        self.account_metadata(parent);
 
-        // Remove the inode from the filesystem
-        self.remove_inode(inode_id);
+        // Drop the link, removing the inode from the filesystem if it was the last one
+        self.release_link(inode_id);
 
         println!("Removed file. Filesystem:");
         self.tree();
         reply.ok();
     }
 
+    /// Create a hard link to an existing file.
+    /// The method adds a new entry to the `new_parent` directory pointing at the
+    /// same inode and increments its link count.
+    ///
+    /// The `ino` parameter is the inode number of the file being linked,
+    /// and `new_name` is the name of the new entry.
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        new_parent: u64,
+        new_name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let name_str = match VFFS::validate_and_return_node_name(new_name) {
+            Ok(name) => name,
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+
+        // Directories cannot be hard linked
+        let file_type = match self.lookup_node(ino) {
+            Ok(inode) => {
+                if inode.is_directory() {
+                    reply.error(libc::EPERM);
+                    return;
+                }
+                FileType::from(&inode.data)
+            }
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+
+        // Inodes cannot be linked into another project, which would dodge its quota
+        if let Ok(parent_inode) = self.lookup_node(new_parent) {
+            if parent_inode.project != 0
+                && self.lookup_node(ino).unwrap().project != parent_inode.project
+            {
+                reply.error(libc::EXDEV);
+                return;
+            }
+        }
+
+        // Adding an entry requires write and search permission on the new parent directory
+        if let Err(err) = self.check_access(req, new_parent, libc::W_OK | libc::X_OK) {
+            reply.error(err);
+            return;
+        }
+
+        // Check if the new parent is a directory without an entry with the same name
+        match self.lookup_node(new_parent) {
+            Ok(parent_inode) => match &parent_inode.data {
+                InodeData::Directory(dir) => {
+                    if dir.find_node_by_name(&name_str).is_some() {
+                        reply.error(libc::EEXIST);
+                        return;
+                    }
+                }
+                _ => {
+                    reply.error(libc::ENOTDIR);
+                    return;
+                }
+            },
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        }
+
+        let growth = [(new_parent, Directory::entry_size(&name_str) as i64)];
+        if let Err(err) = self.check_metadata_growth(&growth, None) {
+            reply.error(err);
+            return;
+        }
+
+        // Link the inode to the new parent
+        if let Ok(parent_inode) = self.lookup_node_mut(new_parent) {
+            parent_inode.append_file_to_directory((ino, name_str, file_type));
+            parent_inode.update_changes();
+        }
+        self.account_metadata(new_parent);
+
+        let inode = self.lookup_node_mut(ino).expect("Inode checked above");
+        inode.hardlinks += 1;
+        inode.lookups += 1;
+        inode.metadata_change_at = time_now();
+
+        let attr_reply: FileAttr = (&*inode).into();
+
+        reply.entry(&Duration::new(0, 0), &attr_reply, 0);
+    }
+
     /// Write data to a file.
     /// This is done by locating the inode in the VFFS, matching it as a file,
-    /// and writing the received data to the file's data buffer.
+    /// and writing the received data to the file at the given offset.
     fn write(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -986,8 +2684,19 @@ impl Filesystem for VFFS {
         //     data.len()
         // );
 
-        match self.write_file_data(ino, data) {
+        if offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        match self.write_file_data(ino, offset as u64, data) {
             Ok(_) => {
+                // Writing by an unprivileged user drops the setuid and setgid bits
+                if req.uid() != 0 {
+                    if let Ok(inode) = self.lookup_node_mut(ino) {
+                        inode.clear_setid_bits();
+                    }
+                }
                 // println!("Wrote {} bytes to inode {}", data.len(), ino);
                 reply.written(data.len() as u32);
             }
@@ -996,6 +2705,241 @@ impl Filesystem for VFFS {
             }
         }
     }
+
+    /// Preallocate or deallocate space of a file.
+    /// The `mode` flags select between preallocation, hole punching and
+    /// range zeroing, as described in `fallocate(2)`.
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        if offset < 0 || length <= 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        match self.fallocate_file_data(ino, offset as u64, length as u64, mode) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    /// Copy a range of data from one file to another.
+    /// The copy shares the source chunks with the destination file,
+    /// so the data is only duplicated once either file writes to it.
+    fn copy_file_range(
+        &mut self,
+        _req: &Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        if offset_in < 0 || offset_out < 0 || flags != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        // The reply can only report a 32-bit amount of copied bytes
+        let len = std::cmp::min(len, u64::from(u32::MAX));
+
+        match self.copy_file_data(ino_in, offset_in as u64, ino_out, offset_out as u64, len) {
+            Ok(copied) => reply.written(copied as u32),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    /// Find the next data or hole region of a file.
+    /// Only `SEEK_DATA` and `SEEK_HOLE` are handled here, as the kernel
+    /// resolves the other `whence` values by itself. The answer comes from
+    /// the allocated chunks of the file, with an implicit hole at its end.
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        if offset < 0 {
+            reply.error(libc::ENXIO);
+            return;
+        }
+        let offset = offset as u64;
+
+        let inode = match self.lookup_node(ino) {
+            Ok(inode) => inode,
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+
+        let virtual_file = match &inode.data {
+            InodeData::File(virtual_file) => virtual_file,
+            InodeData::Directory(_) => {
+                reply.error(libc::EISDIR);
+                return;
+            }
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        if offset >= inode.size {
+            reply.error(libc::ENXIO);
+            return;
+        }
+
+        match whence {
+            libc::SEEK_DATA => match virtual_file.next_data(offset) {
+                Some(data_offset) if data_offset < inode.size => reply.offset(data_offset as i64),
+                _ => reply.error(libc::ENXIO),
+            },
+            libc::SEEK_HOLE => {
+                let hole_offset = std::cmp::min(virtual_file.next_hole(offset), inode.size);
+                reply.offset(hole_offset as i64);
+            }
+            _ => reply.error(libc::EINVAL),
+        }
+    }
+
+    /// Set an extended attribute of a file or directory.
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let groups = match self.lookup_node(ino) {
+            Ok(inode) => VFFS::request_groups_for(req, inode),
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+
+        match self.set_xattr(req.uid(), &groups, ino, name.as_bytes(), value, flags) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    /// Get an extended attribute of a file or directory.
+    /// When `size` is 0 only the size of the value is returned,
+    /// so the caller can allocate a large enough buffer.
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let inode = match self.lookup_node(ino) {
+            Ok(inode) => inode,
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+
+        let groups = VFFS::request_groups_for(req, inode);
+        if let Err(err) =
+            VFFS::check_xattr_access(req.uid(), &groups, inode, name.as_bytes(), false)
+        {
+            reply.error(err);
+            return;
+        }
+
+        // Report the quotas of the owners of the inode and its project quota id,
+        // which are not stored as attributes
+        let computed = if name.as_bytes() == QUOTA_XATTR {
+            Some(
+                self.quotas
+                    .report(&inode.quota_ids(), time_now().0)
+                    .into_bytes(),
+            )
+        } else if name.as_bytes() == PROJECT_XATTR && inode.project != 0 {
+            Some(inode.project.to_string().into_bytes())
+        } else {
+            None
+        };
+
+        match computed
+            .as_ref()
+            .or_else(|| inode.xattrs.get(name.as_bytes()))
+        {
+            Some(value) if size == 0 => reply.size(value.len() as u32),
+            Some(value) if value.len() > size as usize => reply.error(libc::ERANGE),
+            Some(value) => reply.data(value),
+            None => reply.error(libc::ENODATA),
+        }
+    }
+
+    /// List the extended attribute names of a file or directory.
+    /// The names are returned as a sequence of NUL terminated strings, leaving out
+    /// the ones the requesting user cannot access. When `size` is 0 only the size
+    /// of the list is returned.
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let inode = match self.lookup_node(ino) {
+            Ok(inode) => inode,
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+
+        let groups = VFFS::request_groups_for(req, inode);
+        let mut names: Vec<u8> = Vec::new();
+        for name in inode.xattrs.keys() {
+            if VFFS::check_xattr_access(req.uid(), &groups, inode, name, false).is_ok() {
+                names.extend_from_slice(name);
+                names.push(0);
+            }
+        }
+
+        if size == 0 {
+            reply.size(names.len() as u32);
+        } else if names.len() > size as usize {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(&names);
+        }
+    }
+
+    /// Remove an extended attribute of a file or directory.
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let groups = match self.lookup_node(ino) {
+            Ok(inode) => VFFS::request_groups_for(req, inode),
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+
+        match self.remove_xattr(req.uid(), &groups, ino, name.as_bytes()) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -1005,13 +2949,25 @@ pub struct Inode {
     updated_at: (i64, u32),
     accessed_at: (i64, u32),
     metadata_change_at: (i64, u32),
+    created_at: (i64, u32),
     data: InodeData,
     // Permissions and special mode bits
     pub mode: u16,
     pub hardlinks: u32,
     pub uid: u32,
     pub gid: u32,
+    // Project quota id of the inode, or 0 when it belongs to no project
+    pub project: u32,
     pub xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+    // References still held by the kernel and by open file handles.
+    // An inode without links is only reclaimed once both reach zero.
+    lookups: u64,
+    open_handles: u64,
+    // Metadata bytes currently charged against the memory limit for this inode
+    charged_metadata: u64,
+    // File bytes charged to the quotas of the owners of this inode, which count
+    // chunks shared with other files in full
+    charged_data: u64,
 }
 
 impl Clone for Inode {
@@ -1022,15 +2978,18 @@ impl Clone for Inode {
             updated_at: self.updated_at,
             accessed_at: self.accessed_at,
             metadata_change_at: self.metadata_change_at,
-            data: match &self.data {
-                InodeData::File(file) => InodeData::File(file.clone()),
-                InodeData::Directory(directory) => InodeData::Directory(directory.clone()),
-            },
+            created_at: self.created_at,
+            data: self.data.clone(),
             mode: self.mode,
             hardlinks: self.hardlinks,
             uid: self.uid,
             gid: self.gid,
+            project: self.project,
             xattrs: self.xattrs.clone(),
+            lookups: self.lookups,
+            open_handles: self.open_handles,
+            charged_metadata: self.charged_metadata,
+            charged_data: self.charged_data,
         }
     }
 }
@@ -1039,18 +2998,19 @@ impl From<Inode> for FileAttr {
     fn from(attrs: Inode) -> Self {
         FileAttr {
             ino: attrs.id,
-            size: attrs.size,
-            blocks: attrs.size.div_ceil(u64::from(BLOCK_SIZE)),
+            size: attrs.get_size(),
+            blocks: attrs.blocks(),
             atime: system_time_from_time(attrs.accessed_at.0, attrs.accessed_at.1),
             mtime: system_time_from_time(attrs.updated_at.0, attrs.updated_at.1),
             ctime: system_time_from_time(attrs.metadata_change_at.0, attrs.metadata_change_at.1),
-            crtime: SystemTime::UNIX_EPOCH,
-            kind: attrs.data.into(),
+            // Fuser only sends the creation time to the kernel on macOS
+            crtime: system_time_from_time(attrs.created_at.0, attrs.created_at.1),
+            kind: (&attrs.data).into(),
             perm: attrs.mode,
             nlink: attrs.hardlinks,
             uid: attrs.uid,
             gid: attrs.gid,
-            rdev: 0,
+            rdev: attrs.rdev(),
             blksize: BLOCK_SIZE,
             flags: 0,
         }
@@ -1061,68 +3021,156 @@ impl From<&Inode> for FileAttr {
     fn from(attrs: &Inode) -> Self {
         FileAttr {
             ino: attrs.id,
-            size: attrs.size,
-            blocks: attrs.size.div_ceil(u64::from(BLOCK_SIZE)),
+            size: attrs.get_size(),
+            blocks: attrs.blocks(),
             atime: system_time_from_time(attrs.accessed_at.0, attrs.accessed_at.1),
             mtime: system_time_from_time(attrs.updated_at.0, attrs.updated_at.1),
             ctime: system_time_from_time(attrs.metadata_change_at.0, attrs.metadata_change_at.1),
-            crtime: SystemTime::UNIX_EPOCH,
-            kind: attrs.data.clone().into(),
+            // Fuser only sends the creation time to the kernel on macOS
+            crtime: system_time_from_time(attrs.created_at.0, attrs.created_at.1),
+            kind: (&attrs.data).into(),
             perm: attrs.mode,
             nlink: attrs.hardlinks,
             uid: attrs.uid,
             gid: attrs.gid,
-            rdev: 0,
+            rdev: attrs.rdev(),
             blksize: BLOCK_SIZE,
             flags: 0,
         }
     }
-}
+}
+
+impl Inode {
+    pub fn new(mode: u8, name: String, serial_number: u64) -> Inode {
+        let data = if mode == DIR_MODE {
+            InodeData::Directory(Directory::new(name, serial_number))
+        } else {
+            InodeData::File(File::new(name))
+        };
+        Inode::with_data(serial_number, data, 0o777, 0, 0)
+    }
+
+    /// Build an inode owned by `uid` and `gid` with every timestamp set to now.
+    /// It starts with one link, or two for a directory counting its "." entry,
+    /// and a symbolic link takes the length of its target as its size.
+    pub fn with_data(id: u64, data: InodeData, mode: u16, uid: u32, gid: u32) -> Inode {
+        let now = time_now();
+        let (size, hardlinks) = match &data {
+            InodeData::Directory(_) => (0, 2),
+            InodeData::Symlink(symlink) => (symlink.target.len() as u64, 1),
+            _ => (0, 1),
+        };
+
+        Inode {
+            id,
+            size,
+            updated_at: now,
+            accessed_at: now,
+            metadata_change_at: now,
+            created_at: now,
+            data,
+            mode,
+            hardlinks,
+            uid,
+            gid,
+            project: 0,
+            xattrs: BTreeMap::default(),
+            lookups: 0,
+            open_handles: 0,
+            charged_metadata: 0,
+            charged_data: 0,
+        }
+    }
+
+    pub fn get_name(&self) -> &String {
+        match &self.data {
+            InodeData::File(file) => &file.name,
+            InodeData::Directory(directory) => &directory.name,
+            InodeData::Symlink(symlink) => &symlink.name,
+            InodeData::Fifo(special)
+            | InodeData::Socket(special)
+            | InodeData::CharDevice(special)
+            | InodeData::BlockDevice(special) => &special.name,
+        }
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        match &mut self.data {
+            InodeData::File(file) => file.name = name,
+            InodeData::Directory(directory) => directory.name = name,
+            InodeData::Symlink(symlink) => symlink.name = name,
+            InodeData::Fifo(special)
+            | InodeData::Socket(special)
+            | InodeData::CharDevice(special)
+            | InodeData::BlockDevice(special) => special.name = name,
+        }
+    }
+
+    /// Device number of character and block device inodes, or 0 for any other kind.
+    pub fn rdev(&self) -> u32 {
+        match &self.data {
+            InodeData::CharDevice(device) | InodeData::BlockDevice(device) => device.rdev,
+            _ => 0,
+        }
+    }
+
+    /// Owners charged for the bytes and the inode count of this inode.
+    pub fn quota_ids(&self) -> Vec<QuotaId> {
+        let mut ids = vec![QuotaId::User(self.uid), QuotaId::Group(self.gid)];
+        if self.project != 0 {
+            ids.push(QuotaId::Project(self.project));
+        }
+        ids
+    }
+
+    /// Bytes charged to the quotas of the owners of this inode.
+    pub fn quota_bytes(&self) -> u64 {
+        self.charged_metadata + self.charged_data
+    }
 
-impl Inode {
-    pub fn new(mode: u8, name: String, serial_number: u64) -> Inode {
-        if mode == DIR_MODE {
-            let size = (size_of::<Inode>() + size_of::<Directory>()) as u64;
-            Inode {
-                id: serial_number,
-                size,
-                updated_at: time_now(),
-                accessed_at: time_now(),
-                metadata_change_at: time_now(),
-                data: InodeData::Directory(Directory::new(name)),
-                mode: 0o777,
-                hardlinks: 0,
-                uid: 0,
-                gid: 0,
-                xattrs: BTreeMap::default(),
-            }
-        } else {
-            let size = (size_of::<Inode>() + size_of::<File>()) as u64;
-            Inode {
-                id: serial_number,
-                size,
-                updated_at: time_now(),
-                accessed_at: time_now(),
-                metadata_change_at: time_now(),
-                data: InodeData::File(File::new(name)),
-                mode: 0o777,
-                hardlinks: 0,
-                uid: 0,
-                gid: 0,
-                xattrs: BTreeMap::default(),
-            }
-        }
+    /// Memory used by an extended attribute entry with the given name and value.
+    pub fn xattr_size(name: &[u8], value: &[u8]) -> u64 {
+        (size_of::<(Vec<u8>, Vec<u8>)>() + name.len() + value.len()) as u64
     }
 
-    pub fn get_name(&self) -> &String {
+    /// Memory used by the extended attributes of the inode.
+    pub fn xattrs_size(&self) -> u64 {
+        self.xattrs
+            .iter()
+            .map(|(name, value)| Inode::xattr_size(name, value))
+            .sum()
+    }
+
+    /// Memory used by everything in the inode but the contents of a file: the inode
+    /// itself, its name, its extended attributes, the entries of a directory and the
+    /// target of a symbolic link.
+    pub fn metadata_size(&self) -> u64 {
+        let data_size = match &self.data {
+            InodeData::Directory(directory) => directory.entries_size,
+            InodeData::Symlink(symlink) => symlink.target.len() as u64,
+            _ => 0,
+        };
+        size_of::<Inode>() as u64 + self.get_name().len() as u64 + self.xattrs_size() + data_size
+    }
+
+    /// Size reported to users. Directories report a nominal size per entry,
+    /// unrelated to the memory they use.
+    pub fn get_size(&self) -> u64 {
         match &self.data {
-            InodeData::File(file) => &file.name,
-            InodeData::Directory(directory) => &directory.name,
+            InodeData::Directory(directory) => {
+                (directory.nodes.len() as u64 + 2) * DIRECTORY_ENTRY_APPARENT_SIZE
+            }
+            _ => self.size,
         }
     }
 
-    pub fn get_size(&self) -> u64 {
-        self.size
+    /// Number of 512 byte blocks reported to users. Files count the chunks they
+    /// hold, so holes are left out, while other inodes count their apparent size.
+    pub fn blocks(&self) -> u64 {
+        match &self.data {
+            InodeData::File(file) => file.allocated_size() / u64::from(BLOCK_SIZE),
+            _ => self.get_size().div_ceil(u64::from(BLOCK_SIZE)),
+        }
     }
 
     pub fn is_file(&self) -> bool {
@@ -1139,6 +3187,26 @@ impl Inode {
         }
     }
 
+    /// Drop the setuid and setgid bits of a regular file, as Linux does after a write
+    /// or an ownership change. A setgid bit without group execute marks mandatory
+    /// locking rather than privileges, so it is kept.
+    pub fn clear_setid_bits(&mut self) {
+        if !self.is_file() {
+            return;
+        }
+
+        let setgid_exec = libc::S_ISGID as u16 | libc::S_IXGRP as u16;
+        let mut mode = self.mode & !(libc::S_ISUID as u16);
+        if mode & setgid_exec == setgid_exec {
+            mode &= !(libc::S_ISGID as u16);
+        }
+
+        if mode != self.mode {
+            self.mode = mode;
+            self.metadata_change_at = time_now();
+        }
+    }
+
     pub fn update_changes(&mut self) {
         let now = time_now();
         self.updated_at = now;
@@ -1153,7 +3221,9 @@ impl Inode {
     pub fn append_file_to_directory(&mut self, file: (u64, String, FileType)) {
         match &mut self.data {
             InodeData::Directory(directory) => {
-                directory.add_node(file);
+                if !directory.add_node(file) {
+                    eprintln!("Error: trying to append a duplicate name to a directory");
+                }
             }
             _ => {
                 eprintln!("Error: trying to append a file to a non-directory inode");
@@ -1165,66 +3235,520 @@ impl Inode {
 #[derive(Debug, Clone)]
 pub struct File {
     name: String,
-    data: String,
+    // Allocated chunks of the file keyed by their index (offset / CHUNK_SIZE).
+    // Missing chunks are holes and read back as zeros. Chunks may be shared
+    // with other files after a copy, and are copied on the first write.
+    chunks: BTreeMap<u64, Rc<Vec<u8>>>,
+}
+
+/// Bytes released by dropping a chunk reference, which is only the case
+/// when no other file shares it.
+fn released_chunk_bytes(chunk: &Rc<Vec<u8>>) -> i64 {
+    if Rc::strong_count(chunk) == 1 {
+        CHUNK_SIZE as i64
+    } else {
+        0
+    }
 }
 
 impl File {
     pub fn new(name: String) -> File {
         File {
             name,
-            data: String::new(),
+            chunks: BTreeMap::new(),
+        }
+    }
+
+    pub fn new_with_data(name: String, data: Vec<u8>) -> File {
+        let mut file = File::new(name);
+        file.write_at(0, &data);
+        file
+    }
+
+    /// Number of bytes held only by this file, which are released when it is removed.
+    /// A chunk copied onto another range of the same file is referenced more than once,
+    /// so each distinct chunk is counted once, when this file holds all its references.
+    pub fn unique_size(&self) -> u64 {
+        let mut references: HashMap<*const Vec<u8>, (usize, usize)> = HashMap::new();
+        for chunk in self.chunks.values() {
+            references
+                .entry(Rc::as_ptr(chunk))
+                .or_insert((0, Rc::strong_count(chunk)))
+                .0 += 1;
+        }
+
+        references
+            .values()
+            .filter(|(held, total)| held == total)
+            .count() as u64
+            * CHUNK_SIZE
+    }
+
+    /// Number of bytes in the chunks of the file, including the ones shared with other files.
+    pub fn allocated_size(&self) -> u64 {
+        self.chunks.len() as u64 * CHUNK_SIZE
+    }
+
+    /// Number of bytes that would have to be allocated to store the `[start, end)` range.
+    pub fn unallocated_bytes_in(&self, start: u64, end: u64) -> u64 {
+        if start >= end {
+            return 0;
+        }
+
+        let first_chunk = start / CHUNK_SIZE;
+        let last_chunk = (end - 1) / CHUNK_SIZE;
+        let allocated = self.chunks.range(first_chunk..=last_chunk).count() as u64;
+
+        (last_chunk - first_chunk + 1 - allocated) * CHUNK_SIZE
+    }
+
+    /// Number of bytes that would have to be copied before modifying the `[start, end)` range,
+    /// because the chunks covering it are shared with another file.
+    pub fn shared_bytes_in(&self, start: u64, end: u64) -> u64 {
+        if start >= end {
+            return 0;
+        }
+
+        let first_chunk = start / CHUNK_SIZE;
+        let last_chunk = (end - 1) / CHUNK_SIZE;
+
+        self.chunks
+            .range(first_chunk..=last_chunk)
+            .filter(|(_, chunk)| Rc::strong_count(chunk) > 1)
+            .count() as u64
+            * CHUNK_SIZE
+    }
+
+    /// Number of bytes that would have to be allocated to write the `[start, end)` range.
+    pub fn write_cost(&self, start: u64, end: u64) -> u64 {
+        self.unallocated_bytes_in(start, end) + self.shared_bytes_in(start, end)
+    }
+
+    /// Number of bytes that would have to be copied to zero the `[start, end)` range,
+    /// which only happens for shared chunks partially covered at its edges.
+    pub fn zero_cost(&self, start: u64, end: u64) -> u64 {
+        if start >= end {
+            return 0;
+        }
+
+        let mut cost = 0;
+        if start % CHUNK_SIZE != 0 {
+            cost += self.shared_bytes_in(start, start + 1);
+        }
+        if end % CHUNK_SIZE != 0 && (end - 1) / CHUNK_SIZE != start / CHUNK_SIZE {
+            cost += self.shared_bytes_in(end - 1, end);
+        }
+        cost
+    }
+
+    /// Get the chunk at `index` ready to be modified, allocating it if it is a hole
+    /// and copying it if it is shared. Returns the chunk and the bytes allocated.
+    fn chunk_mut(&mut self, index: u64) -> (&mut Vec<u8>, i64) {
+        let mut allocated = 0;
+
+        let chunk = self.chunks.entry(index).or_insert_with(|| {
+            allocated += CHUNK_SIZE as i64;
+            Rc::new(vec![0; CHUNK_SIZE as usize])
+        });
+        if Rc::strong_count(chunk) > 1 {
+            allocated += CHUNK_SIZE as i64;
+        }
+
+        (Rc::make_mut(chunk), allocated)
+    }
+
+    /// Write `data` starting at `offset`, allocating the chunks it covers.
+    /// Returns the change in allocated bytes.
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> i64 {
+        let mut written = 0;
+        let mut allocated = 0;
+
+        while written < data.len() {
+            let position = offset + written as u64;
+            let chunk_offset = (position % CHUNK_SIZE) as usize;
+            let to_write = std::cmp::min(CHUNK_SIZE as usize - chunk_offset, data.len() - written);
+
+            let (chunk, chunk_allocated) = self.chunk_mut(position / CHUNK_SIZE);
+            chunk[chunk_offset..chunk_offset + to_write]
+                .copy_from_slice(&data[written..written + to_write]);
+
+            allocated += chunk_allocated;
+            written += to_write;
+        }
+
+        allocated
+    }
+
+    /// Read `size` bytes starting at `offset`, filling holes with zeros.
+    pub fn read_at(&self, offset: u64, size: u64) -> Vec<u8> {
+        let mut buffer = vec![0u8; size as usize];
+        if size == 0 {
+            return buffer;
+        }
+
+        let end = offset + size;
+        let first_chunk = offset / CHUNK_SIZE;
+        let last_chunk = (end - 1) / CHUNK_SIZE;
+
+        for (index, chunk) in self.chunks.range(first_chunk..=last_chunk) {
+            let chunk_start = index * CHUNK_SIZE;
+            let copy_start = std::cmp::max(chunk_start, offset);
+            let copy_end = std::cmp::min(chunk_start + CHUNK_SIZE, end);
+
+            buffer[(copy_start - offset) as usize..(copy_end - offset) as usize].copy_from_slice(
+                &chunk[(copy_start - chunk_start) as usize..(copy_end - chunk_start) as usize],
+            );
+        }
+
+        buffer
+    }
+
+    /// Make sure every chunk covering the `[start, end)` range is allocated.
+    /// Returns the change in allocated bytes.
+    pub fn allocate(&mut self, start: u64, end: u64) -> i64 {
+        if start >= end {
+            return 0;
+        }
+
+        let mut allocated = 0;
+        for index in start / CHUNK_SIZE..=(end - 1) / CHUNK_SIZE {
+            self.chunks.entry(index).or_insert_with(|| {
+                allocated += CHUNK_SIZE as i64;
+                Rc::new(vec![0; CHUNK_SIZE as usize])
+            });
+        }
+        allocated
+    }
+
+    /// Deallocate the `[start, end)` range so it reads back as zeros.
+    /// Chunks fully inside the range are released, and the covered part of
+    /// partially overlapping chunks is zeroed.
+    /// Returns the change in allocated bytes.
+    pub fn punch_hole(&mut self, start: u64, end: u64) -> i64 {
+        if start >= end {
+            return 0;
+        }
+
+        let first_chunk = start / CHUNK_SIZE;
+        let last_chunk = (end - 1) / CHUNK_SIZE;
+        let indexes: Vec<u64> = self
+            .chunks
+            .range(first_chunk..=last_chunk)
+            .map(|(index, _)| *index)
+            .collect();
+
+        let mut allocated = 0;
+        for index in indexes {
+            let chunk_start = index * CHUNK_SIZE;
+            let zero_start = std::cmp::max(chunk_start, start);
+            let zero_end = std::cmp::min(chunk_start + CHUNK_SIZE, end);
+
+            if zero_end - zero_start == CHUNK_SIZE {
+                if let Some(chunk) = self.chunks.remove(&index) {
+                    allocated -= released_chunk_bytes(&chunk);
+                }
+            } else {
+                let (chunk, chunk_allocated) = self.chunk_mut(index);
+                chunk[(zero_start - chunk_start) as usize..(zero_end - chunk_start) as usize]
+                    .fill(0);
+                allocated += chunk_allocated;
+            }
+        }
+        allocated
+    }
+
+    /// Drop every byte at or past `size`.
+    /// Whole chunks past the new end are released, and the tail of the
+    /// last partial chunk is zeroed so a later extension reads back as zeros.
+    /// Returns the change in allocated bytes.
+    pub fn truncate(&mut self, size: u64) -> i64 {
+        let first_dropped = size.div_ceil(CHUNK_SIZE);
+
+        // Drop the chunks one at a time, so a chunk held at several indexes after
+        // copying the file onto itself is only released with its last reference
+        let mut allocated = 0;
+        let mut dropped = self.chunks.split_off(&first_dropped);
+        while let Some((_, chunk)) = dropped.pop_first() {
+            allocated -= released_chunk_bytes(&chunk);
+        }
+
+        let last_index = size / CHUNK_SIZE;
+        if size % CHUNK_SIZE != 0 && self.chunks.contains_key(&last_index) {
+            let (chunk, chunk_allocated) = self.chunk_mut(last_index);
+            chunk[(size % CHUNK_SIZE) as usize..].fill(0);
+            allocated += chunk_allocated;
+        }
+        allocated
+    }
+
+    /// Offset of the first allocated byte at or after `offset`, if any.
+    pub fn next_data(&self, offset: u64) -> Option<u64> {
+        self.chunks
+            .range(offset / CHUNK_SIZE..)
+            .next()
+            .map(|(index, _)| std::cmp::max(index * CHUNK_SIZE, offset))
+    }
+
+    /// Offset of the first hole byte at or after `offset`.
+    /// Past the last allocated chunk everything is a hole.
+    pub fn next_hole(&self, offset: u64) -> u64 {
+        let mut index = offset / CHUNK_SIZE;
+        while self.chunks.contains_key(&index) {
+            index += 1;
+        }
+        std::cmp::max(index * CHUNK_SIZE, offset)
+    }
+
+    /// Build a file holding the `[start, end)` range of this one, to be used as a copy source.
+    /// Chunks fully inside the range are shared, while partially covered ones are copied,
+    /// so the slice never shares a chunk that the copy destination could write to.
+    pub fn slice(&self, start: u64, end: u64) -> File {
+        let mut slice = File::new(self.name.clone());
+        if start >= end {
+            return slice;
+        }
+
+        for (index, chunk) in self
+            .chunks
+            .range(start / CHUNK_SIZE..=(end - 1) / CHUNK_SIZE)
+        {
+            let chunk_start = index * CHUNK_SIZE;
+            if chunk_start >= start && chunk_start + CHUNK_SIZE <= end {
+                slice.chunks.insert(*index, Rc::clone(chunk));
+            } else {
+                slice.chunks.insert(*index, Rc::new(chunk.to_vec()));
+            }
+        }
+        slice
+    }
+
+    /// Range of source chunk indexes that a copy can share instead of duplicating.
+    /// Chunks can only be shared when both offsets sit at the same position inside a chunk.
+    fn shareable_chunks(source_offset: u64, offset: u64, length: u64) -> Option<(u64, u64)> {
+        if source_offset % CHUNK_SIZE != offset % CHUNK_SIZE {
+            return None;
+        }
+
+        let first_chunk = source_offset.div_ceil(CHUNK_SIZE);
+        let end_chunk = (source_offset + length) / CHUNK_SIZE;
+        if first_chunk >= end_chunk {
+            return None;
+        }
+        Some((first_chunk, end_chunk))
+    }
+
+    /// Number of bytes that would have to be allocated to copy `length` bytes
+    /// at `source_offset` into this file at `offset`.
+    pub fn copy_cost(&self, source_offset: u64, offset: u64, length: u64) -> u64 {
+        match File::shareable_chunks(source_offset, offset, length) {
+            Some((first_chunk, end_chunk)) => {
+                let shared_start = offset + (first_chunk * CHUNK_SIZE - source_offset);
+                let shared_end = offset + (end_chunk * CHUNK_SIZE - source_offset);
+                self.write_cost(offset, shared_start) + self.write_cost(shared_end, offset + length)
+            }
+            None => self.write_cost(offset, offset + length),
         }
     }
 
-    pub fn new_with_data(name: String, data: String) -> File {
-        File { name, data }
+    /// Number of bytes that copying `length` bytes at `source_offset` of `source` into
+    /// this file at `offset` adds to its chunks, counting the ones shared with the source.
+    pub fn copy_growth(&self, source: &File, source_offset: u64, offset: u64, length: u64) -> u64 {
+        match File::shareable_chunks(source_offset, offset, length) {
+            Some((first_chunk, end_chunk)) => {
+                let shared_start = offset + (first_chunk * CHUNK_SIZE - source_offset);
+                let shared_end = offset + (end_chunk * CHUNK_SIZE - source_offset);
+                let shift = shared_start / CHUNK_SIZE;
+
+                // Shared chunks only fill the holes of this file facing data in the source
+                let filled = (first_chunk..end_chunk)
+                    .filter(|index| {
+                        source.chunks.contains_key(index)
+                            && !self.chunks.contains_key(&(shift + index - first_chunk))
+                    })
+                    .count() as u64;
+
+                self.unallocated_bytes_in(offset, shared_start)
+                    + filled * CHUNK_SIZE
+                    + self.unallocated_bytes_in(shared_end, offset + length)
+            }
+            None => self.unallocated_bytes_in(offset, offset + length),
+        }
     }
 
-    pub fn write_date(&mut self, data: &[u8]) {
-        let data_str = String::from_utf8_lossy(data).to_string();
-        self.data.push_str(&data_str);
+    /// Copy `length` bytes at `source_offset` of `source` into this file at `offset`.
+    /// Whole chunks are shared with the source instead of being copied, and holes
+    /// stay holes, so the copy only costs memory once either file writes to them.
+    /// Returns the change in allocated bytes.
+    pub fn copy_from(
+        &mut self,
+        source: &File,
+        source_offset: u64,
+        offset: u64,
+        length: u64,
+    ) -> i64 {
+        let (first_chunk, end_chunk) = match File::shareable_chunks(source_offset, offset, length) {
+            Some(span) => span,
+            None => return self.write_at(offset, &source.read_at(source_offset, length)),
+        };
+
+        let head_length = first_chunk * CHUNK_SIZE - source_offset;
+        let tail_start = end_chunk * CHUNK_SIZE;
+        let shift = offset + head_length;
+
+        let mut allocated = self.write_at(offset, &source.read_at(source_offset, head_length));
+
+        for index in first_chunk..end_chunk {
+            let target = (shift + (index - first_chunk) * CHUNK_SIZE) / CHUNK_SIZE;
+            let replaced = match source.chunks.get(&index) {
+                Some(chunk) => self.chunks.insert(target, Rc::clone(chunk)),
+                None => self.chunks.remove(&target),
+            };
+            if let Some(chunk) = replaced {
+                allocated -= released_chunk_bytes(&chunk);
+            }
+        }
+
+        let tail_length = source_offset + length - tail_start;
+        allocated += self.write_at(
+            offset + (tail_start - source_offset),
+            &source.read_at(tail_start, tail_length),
+        );
+        allocated
     }
 
     pub fn clone(&self) -> File {
         File {
             name: self.name.clone(),
-            data: self.data.clone(),
+            chunks: self.chunks.clone(),
         }
     }
 }
 
 #[derive(Debug, Clone)]
+/// Directory entries are kept in insertion order under a readdir cookie that never
+/// changes while the entry exists, and indexed by name for constant time lookups.
 pub struct Directory {
     name: String,
-    nodes: Vec<(u64, String, FileType)>,
+    /// Inode of the directory holding this one, which is itself for the root
+    parent: u64,
+    nodes: BTreeMap<u64, (u64, String, FileType)>,
+    cookies_by_name: HashMap<String, u64>,
+    next_cookie: u64,
+    // Memory used by the entries, kept up to date as they are added and removed
+    entries_size: u64,
 }
 
 impl Directory {
-    pub fn new(name: String) -> Directory {
+    pub fn new(name: String, parent: u64) -> Directory {
         Directory {
             name,
-            nodes: Vec::new(),
+            parent,
+            nodes: BTreeMap::new(),
+            cookies_by_name: HashMap::new(),
+            next_cookie: FIRST_ENTRY_COOKIE,
+            entries_size: 0,
         }
     }
 
     pub fn clone(&self) -> Directory {
         Directory {
             name: self.name.clone(),
+            parent: self.parent,
             nodes: self.nodes.clone(),
+            cookies_by_name: self.cookies_by_name.clone(),
+            next_cookie: self.next_cookie,
+            entries_size: self.entries_size,
+        }
+    }
+
+    /// Add an entry under a new cookie. Returns false, leaving the directory
+    /// untouched, when an entry with the same name already exists.
+    pub fn add_node(&mut self, inode: (u64, String, FileType)) -> bool {
+        if self.cookies_by_name.contains_key(&inode.1) {
+            return false;
         }
+
+        let cookie = self.next_cookie;
+        self.next_cookie += 1;
+        self.entries_size += Directory::entry_size(&inode.1);
+        self.cookies_by_name.insert(inode.1.clone(), cookie);
+        self.nodes.insert(cookie, inode);
+        true
+    }
+
+    pub fn remove_node_by_name(&mut self, name: &str) -> Option<(u64, String, FileType)> {
+        let cookie = self.cookies_by_name.remove(name)?;
+        self.entries_size -= Directory::entry_size(name);
+        self.nodes.remove(&cookie)
     }
 
-    pub fn add_node(&mut self, inode: (u64, String, FileType)) {
-        self.nodes.push(inode);
+    /// Memory used by an entry with the given name, counting both the entry
+    /// and its place in the name index.
+    pub fn entry_size(name: &str) -> u64 {
+        (size_of::<(u64, (u64, String, FileType))>() + size_of::<(String, u64)>() + 2 * name.len())
+            as u64
     }
 
     pub fn find_node_by_name(&self, name: &str) -> Option<(u64, String, FileType)> {
-        for file in &self.nodes {
-            if file.1 == name {
-                return Some(file.clone());
-            }
-        }
-        None
+        let cookie = self.cookies_by_name.get(name)?;
+        self.nodes.get(cookie).cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &(u64, String, FileType)> {
+        self.nodes.values()
+    }
+
+    /// Entries that follow the readdir cookie `offset`, starting with "." and ".."
+    /// for the directory inode `id`, each one along with its own cookie.
+    pub fn entries_after(
+        &self,
+        id: u64,
+        offset: i64,
+    ) -> impl Iterator<Item = (u64, u64, &str, FileType)> + '_ {
+        let offset = offset.max(0) as u64;
+        let dot_entries = [
+            (1, id, ".", FileType::Directory),
+            (2, self.parent, "..", FileType::Directory),
+        ];
+
+        dot_entries
+            .into_iter()
+            .filter(move |entry| entry.0 > offset)
+            .chain(
+                self.nodes
+                    .range(offset + 1..)
+                    .map(|(cookie, (id, name, file_type))| {
+                        (*cookie, *id, name.as_str(), *file_type)
+                    }),
+            )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Symlink {
+    name: String,
+    target: Vec<u8>,
+}
+
+impl Symlink {
+    pub fn new(name: String, target: Vec<u8>) -> Symlink {
+        Symlink { name, target }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpecialFile {
+    name: String,
+    rdev: u32,
+}
+
+impl SpecialFile {
+    pub fn new(name: String, rdev: u32) -> SpecialFile {
+        SpecialFile { name, rdev }
     }
 }
 
@@ -1257,6 +3781,30 @@ fn main() {
                 .help("Sets the maximum file size in MB")
                 .default_value("1"),
         )
+        .arg(
+            Arg::new("max-inodes")
+                .long("max-inodes")
+                .help("Sets the maximum number of inodes"),
+        )
+        .arg(
+            Arg::new("quota")
+                .long("quota")
+                .value_name("QUOTA")
+                .action(ArgAction::Append)
+                .help("Sets a user, group or project quota as <user|group|project>:<id>:<soft MB>:<hard MB>:<soft inodes>:<hard inodes>"),
+        )
+        .arg(
+            Arg::new("quota-file")
+                .long("quota-file")
+                .value_name("PATH")
+                .help("Reads quotas from a file with one quota per line"),
+        )
+        .arg(
+            Arg::new("quota-grace")
+                .long("quota-grace")
+                .value_name("SECONDS")
+                .help("Sets how long soft quota limits may be exceeded, in seconds"),
+        )
         .get_matches();
 
     let mem_limit: u64 = matches
@@ -1273,6 +3821,30 @@ fn main() {
         .expect("File size limit must be a number");
     set_max_file_size(file_size_limit);
 
+    if let Some(max_inodes) = matches.get_one::<String>("max-inodes") {
+        let inode_limit: u64 = max_inodes.parse().expect("Inode limit must be a number");
+        set_max_inodes(inode_limit);
+    }
+
+    let grace_period: i64 = match matches.get_one::<String>("quota-grace") {
+        Some(grace) => grace.parse().expect("Quota grace period must be a number"),
+        None => DEFAULT_GRACE_PERIOD,
+    };
+    let mut quotas = Quotas::new(grace_period);
+
+    let mut quota_limits = Vec::new();
+    if let Some(path) = matches.get_one::<String>("quota-file") {
+        quota_limits.extend(Quotas::parse_file(path).unwrap_or_else(|err| panic!("{err}")));
+    }
+    if let Some(specs) = matches.get_many::<String>("quota") {
+        for spec in specs {
+            quota_limits.push(Quotas::parse_spec(spec).unwrap_or_else(|err| panic!("{err}")));
+        }
+    }
+    for (id, limits) in quota_limits {
+        quotas.set_limits(id, limits);
+    }
+
     let verbosity = matches.get_count("v");
     let log_level = match verbosity {
         0 => LevelFilter::Error,
@@ -1293,5 +3865,545 @@ fn main() {
 
     let options = vec![MountOption::FSName("VFFS".to_string())];
 
-    fuser::mount2(VFFS::new(&mountpoint), mountpoint, &options).unwrap();
+    fuser::mount2(VFFS::new(&mountpoint, quotas), mountpoint, &options).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quota::QuotaLimits;
+
+    /// Empty filesystem with limits large enough to stay out of the way.
+    fn test_filesystem(quotas: Quotas) -> VFFS {
+        set_max_memory(1024);
+        set_max_file_size(1024);
+        VFFS::new(&"test".to_string(), quotas)
+    }
+
+    /// Add an empty regular file owned by `uid` to the filesystem, without linking it
+    /// to any directory, and return its inode id.
+    fn add_file(vffs: &mut VFFS, id: u64, uid: u32) -> u64 {
+        vffs.append_inode(file(id, uid));
+        id
+    }
+
+    /// Add an inode to the filesystem and link it to `parent` under its name,
+    /// counting the ".." link of a directory in the parent, and return its inode id.
+    fn add_entry(vffs: &mut VFFS, parent: u64, inode: Inode) -> u64 {
+        let id = inode.id;
+        let entry = (id, inode.get_name().clone(), FileType::from(&inode.data));
+        vffs.append_inode(inode);
+
+        let parent_inode = vffs.lookup_node_mut(parent).unwrap();
+        if entry.2 == FileType::Directory {
+            parent_inode.hardlinks += 1;
+        }
+        parent_inode.append_file_to_directory(entry);
+        vffs.account_metadata(parent);
+        id
+    }
+
+    /// Directory inode named `dir<id>` owned by `uid`, below `parent`.
+    fn directory(id: u64, parent: u64, uid: u32) -> Inode {
+        let data = InodeData::Directory(Directory::new(format!("dir{id}"), parent));
+        Inode::with_data(id, data, 0o755, uid, uid)
+    }
+
+    /// Regular file inode named `file<id>` owned by `uid`.
+    fn file(id: u64, uid: u32) -> Inode {
+        let data = InodeData::File(File::new(format!("file{id}")));
+        Inode::with_data(id, data, 0o644, uid, uid)
+    }
+
+    /// Extended attribute value of an ACL made of `(tag, permissions, id)` entries.
+    fn acl_value(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut value = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    #[test]
+    fn copying_a_file_onto_itself_releases_every_chunk_on_removal() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let empty_size = vffs.size;
+        let file_id = add_file(&mut vffs, 100, 1000);
+        let data = vec![7; 2 * CHUNK_SIZE as usize];
+
+        assert_eq!(vffs.write_file_data(file_id, 0, &data), Ok(()));
+        assert_eq!(
+            vffs.copy_file_data(file_id, 0, file_id, 2 * CHUNK_SIZE, 2 * CHUNK_SIZE),
+            Ok(2 * CHUNK_SIZE)
+        );
+
+        vffs.remove_inode(file_id);
+        assert_eq!(vffs.size, empty_size);
+    }
+
+    #[test]
+    fn copying_overlapping_ranges_of_a_file_is_rejected() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let file_id = add_file(&mut vffs, 100, 1000);
+        let data = vec![7; 2 * CHUNK_SIZE as usize];
+
+        assert_eq!(vffs.write_file_data(file_id, 0, &data), Ok(()));
+        assert_eq!(
+            vffs.copy_file_data(file_id, 0, file_id, CHUNK_SIZE, CHUNK_SIZE * 2),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(
+            vffs.copy_file_data(file_id, CHUNK_SIZE, file_id, 0, CHUNK_SIZE),
+            Ok(CHUNK_SIZE)
+        );
+    }
+
+    #[test]
+    fn copies_stay_charged_to_their_owner_once_the_source_is_removed() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let source_id = add_file(&mut vffs, 100, 1000);
+        let copy_id = add_file(&mut vffs, 101, 1001);
+        let data = vec![7; 4 * CHUNK_SIZE as usize];
+
+        assert_eq!(vffs.write_file_data(source_id, 0, &data), Ok(()));
+        assert_eq!(
+            vffs.copy_file_data(source_id, 0, copy_id, 0, 4 * CHUNK_SIZE),
+            Ok(4 * CHUNK_SIZE)
+        );
+
+        let copy_bytes = vffs.lookup_node(copy_id).unwrap().quota_bytes();
+        assert!(copy_bytes >= 4 * CHUNK_SIZE);
+        assert_eq!(vffs.quotas.usage(QuotaId::User(1001)), (copy_bytes, 1));
+
+        vffs.remove_inode(source_id);
+        assert_eq!(vffs.quotas.usage(QuotaId::User(1000)), (0, 0));
+        assert_eq!(vffs.quotas.usage(QuotaId::User(1001)), (copy_bytes, 1));
+
+        vffs.remove_inode(copy_id);
+        assert_eq!(vffs.quotas.usage(QuotaId::User(1001)), (0, 0));
+    }
+
+    #[test]
+    fn copies_of_shared_chunks_are_limited_by_the_quota_of_their_owner() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let source_id = add_file(&mut vffs, 100, 1000);
+        let copy_id = add_file(&mut vffs, 101, 1001);
+        let data = vec![7; 4 * CHUNK_SIZE as usize];
+
+        let limits = QuotaLimits {
+            hard_bytes: vffs.lookup_node(copy_id).unwrap().quota_bytes() + 2 * CHUNK_SIZE,
+            ..QuotaLimits::default()
+        };
+        vffs.quotas.set_limits(QuotaId::User(1001), limits);
+
+        assert_eq!(vffs.write_file_data(source_id, 0, &data), Ok(()));
+        assert_eq!(
+            vffs.copy_file_data(source_id, 0, copy_id, 0, 4 * CHUNK_SIZE),
+            Err(libc::EDQUOT)
+        );
+        assert_eq!(
+            vffs.copy_file_data(source_id, 0, copy_id, 0, 2 * CHUNK_SIZE),
+            Ok(2 * CHUNK_SIZE)
+        );
+    }
+
+    #[test]
+    fn an_access_acl_that_does_not_fit_leaves_the_mode_untouched() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let file_id = add_file(&mut vffs, 100, 1000);
+        let acl = acl_value(&[
+            (0x01, 0o7, u32::MAX),
+            (0x02, 0o6, 1001),
+            (0x04, 0o4, u32::MAX),
+            (0x10, 0o6, u32::MAX),
+            (0x20, 0o0, u32::MAX),
+        ]);
+
+        vffs.size = get_max_memory();
+        assert_eq!(
+            vffs.set_xattr(1000, &[1000], file_id, ACCESS_ACL_XATTR, &acl, 0),
+            Err(libc::ENOSPC)
+        );
+        assert_eq!(vffs.lookup_node(file_id).unwrap().mode, 0o644);
+    }
+
+    #[test]
+    fn user_attributes_follow_the_permissions_of_the_inode() {
+        let data = InodeData::File(File::new("file".to_string()));
+        let inode = Inode::with_data(100, data, 0o640, 1000, 1000);
+        let name = b"user.comment";
+
+        assert_eq!(
+            VFFS::check_xattr_access(1000, &[1000], &inode, name, true),
+            Ok(())
+        );
+        assert_eq!(
+            VFFS::check_xattr_access(2000, &[1000], &inode, name, false),
+            Ok(())
+        );
+        assert_eq!(
+            VFFS::check_xattr_access(2000, &[1000], &inode, name, true),
+            Err(libc::EACCES)
+        );
+        assert_eq!(
+            VFFS::check_xattr_access(2000, &[2000], &inode, name, false),
+            Err(libc::EACCES)
+        );
+    }
+
+    #[test]
+    fn file_reads_back_writes_across_chunk_edges_and_holes() {
+        let mut file = File::new("file".to_string());
+        let data: Vec<u8> = (0..CHUNK_SIZE + 200).map(|byte| byte as u8).collect();
+
+        let offset = 3 * CHUNK_SIZE - 100;
+        assert_eq!(file.write_at(offset, &data), 3 * CHUNK_SIZE as i64);
+        assert_eq!(file.read_at(offset, data.len() as u64), data);
+
+        // Everything before the write is a hole reading back as zeros
+        assert_eq!(
+            file.read_at(0, 2 * CHUNK_SIZE),
+            vec![0; 2 * CHUNK_SIZE as usize]
+        );
+        assert_eq!(file.allocated_size(), 3 * CHUNK_SIZE);
+
+        // Rewriting allocated chunks allocates nothing more
+        assert_eq!(file.write_at(offset + 10, &[1, 2, 3]), 0);
+        assert_eq!(
+            file.read_at(offset + 9, 5),
+            vec![data[9], 1, 2, 3, data[13]]
+        );
+    }
+
+    #[test]
+    fn file_truncate_releases_chunks_and_zeroes_the_tail() {
+        let mut file = File::new("file".to_string());
+        file.write_at(0, &vec![9; 3 * CHUNK_SIZE as usize]);
+
+        assert_eq!(file.truncate(CHUNK_SIZE + 10), -(CHUNK_SIZE as i64));
+        assert_eq!(file.allocated_size(), 2 * CHUNK_SIZE);
+        assert_eq!(file.read_at(CHUNK_SIZE + 9, 2), vec![9, 0]);
+
+        // Extending after a truncation reads back zeros past the old end
+        assert_eq!(
+            file.read_at(CHUNK_SIZE + 10, CHUNK_SIZE),
+            vec![0; CHUNK_SIZE as usize]
+        );
+
+        assert_eq!(file.truncate(0), -2 * CHUNK_SIZE as i64);
+        assert_eq!(file.allocated_size(), 0);
+    }
+
+    #[test]
+    fn file_punch_hole_releases_whole_chunks_and_zeroes_partial_ones() {
+        let mut file = File::new("file".to_string());
+        file.write_at(0, &vec![5; 3 * CHUNK_SIZE as usize]);
+
+        assert_eq!(
+            file.punch_hole(CHUNK_SIZE - 10, 2 * CHUNK_SIZE + 10),
+            -(CHUNK_SIZE as i64)
+        );
+        assert_eq!(file.allocated_size(), 2 * CHUNK_SIZE);
+        assert_eq!(file.read_at(CHUNK_SIZE - 11, 2), vec![5, 0]);
+        assert_eq!(file.read_at(2 * CHUNK_SIZE + 9, 2), vec![0, 5]);
+
+        // Punching a hole over holes changes nothing
+        assert_eq!(file.punch_hole(CHUNK_SIZE, 2 * CHUNK_SIZE), 0);
+    }
+
+    #[test]
+    fn file_allocate_fills_holes_only() {
+        let mut file = File::new("file".to_string());
+        file.write_at(CHUNK_SIZE, &[1]);
+
+        assert_eq!(file.unallocated_bytes_in(0, 3 * CHUNK_SIZE), 2 * CHUNK_SIZE);
+        assert_eq!(file.allocate(10, 3 * CHUNK_SIZE), 2 * CHUNK_SIZE as i64);
+        assert_eq!(file.allocated_size(), 3 * CHUNK_SIZE);
+        assert_eq!(file.read_at(CHUNK_SIZE, 1), vec![1]);
+        assert_eq!(file.allocate(0, 3 * CHUNK_SIZE), 0);
+    }
+
+    #[test]
+    fn file_slice_shares_whole_chunks_only() {
+        let mut file = File::new("file".to_string());
+        file.write_at(0, &vec![3; 3 * CHUNK_SIZE as usize]);
+
+        let slice = file.slice(10, 3 * CHUNK_SIZE);
+        assert_eq!(slice.read_at(10, 10), vec![3; 10]);
+        assert_eq!(file.unique_size(), CHUNK_SIZE);
+        assert_eq!(file.shared_bytes_in(0, 3 * CHUNK_SIZE), 2 * CHUNK_SIZE);
+
+        drop(slice);
+        assert_eq!(file.unique_size(), 3 * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn file_copies_share_aligned_chunks_until_written() {
+        let mut source = File::new("source".to_string());
+        source.write_at(0, &vec![4; 2 * CHUNK_SIZE as usize + 100]);
+        let mut target = File::new("target".to_string());
+
+        let length = 2 * CHUNK_SIZE + 100;
+        let slice = source.slice(0, length);
+        assert_eq!(target.copy_cost(0, 0, length), CHUNK_SIZE);
+        assert_eq!(target.copy_growth(&slice, 0, 0, length), 3 * CHUNK_SIZE);
+
+        // Only the partial chunk at the tail is duplicated
+        assert_eq!(target.copy_from(&slice, 0, 0, length), CHUNK_SIZE as i64);
+        drop(slice);
+        assert_eq!(target.read_at(0, length), source.read_at(0, length));
+        assert_eq!(target.allocated_size(), 3 * CHUNK_SIZE);
+        assert_eq!(target.unique_size(), CHUNK_SIZE);
+        assert_eq!(source.unique_size(), CHUNK_SIZE);
+
+        // Writing to a shared chunk copies it, leaving the source untouched
+        assert_eq!(target.write_cost(0, 1), CHUNK_SIZE);
+        assert_eq!(target.write_at(0, &[8]), CHUNK_SIZE as i64);
+        assert_eq!(source.read_at(0, 1), vec![4]);
+        assert_eq!(source.unique_size(), 2 * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn file_copies_between_unaligned_offsets_duplicate_the_data() {
+        let mut source = File::new("source".to_string());
+        source.write_at(0, &vec![6; 2 * CHUNK_SIZE as usize]);
+        let mut target = File::new("target".to_string());
+
+        let slice = source.slice(0, 2 * CHUNK_SIZE);
+        assert_eq!(
+            target.copy_from(&slice, 0, 1, 2 * CHUNK_SIZE),
+            3 * CHUNK_SIZE as i64
+        );
+        drop(slice);
+        assert_eq!(
+            target.read_at(1, 2 * CHUNK_SIZE),
+            source.read_at(0, 2 * CHUNK_SIZE)
+        );
+        assert_eq!(source.unique_size(), 2 * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn file_finds_data_and_holes_around_allocated_chunks() {
+        let mut file = File::new("file".to_string());
+        file.write_at(2 * CHUNK_SIZE, &vec![1; 2 * CHUNK_SIZE as usize]);
+
+        assert_eq!(file.next_data(0), Some(2 * CHUNK_SIZE));
+        assert_eq!(file.next_data(2 * CHUNK_SIZE + 5), Some(2 * CHUNK_SIZE + 5));
+        assert_eq!(file.next_data(4 * CHUNK_SIZE), None);
+
+        assert_eq!(file.next_hole(10), 10);
+        assert_eq!(file.next_hole(2 * CHUNK_SIZE), 4 * CHUNK_SIZE);
+        assert_eq!(file.next_hole(5 * CHUNK_SIZE + 1), 5 * CHUNK_SIZE + 1);
+    }
+
+    #[test]
+    fn truncating_a_file_copied_onto_itself_releases_every_chunk() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let file_id = add_file(&mut vffs, 100, 1000);
+        let empty_size = vffs.size;
+
+        assert_eq!(
+            vffs.write_file_data(file_id, 0, &vec![7; CHUNK_SIZE as usize]),
+            Ok(())
+        );
+        assert_eq!(
+            vffs.copy_file_data(file_id, 0, file_id, 2 * CHUNK_SIZE, CHUNK_SIZE),
+            Ok(CHUNK_SIZE)
+        );
+
+        assert_eq!(vffs.truncate_file_data(file_id, 0), Ok(()));
+        assert_eq!(vffs.size, empty_size);
+    }
+
+    #[test]
+    fn preallocating_past_the_maximum_file_size_fails_even_when_keeping_the_size() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let file_id = add_file(&mut vffs, 100, 1000);
+        let size = vffs.size;
+
+        let length = get_max_file_size() + 1;
+        let mode = libc::FALLOC_FL_KEEP_SIZE;
+        assert_eq!(
+            vffs.fallocate_file_data(file_id, 0, length, mode),
+            Err(libc::EFBIG)
+        );
+        assert_eq!(vffs.size, size);
+    }
+
+    #[test]
+    fn renames_that_grow_the_metadata_must_fit_under_the_memory_limit() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let file_id = add_file(&mut vffs, 100, 1000);
+        vffs.size = get_max_memory() - 4;
+
+        let moved = VFFS::rename_growth(
+            (FUSE_ROOT_ID, "file100", file_id),
+            (FUSE_ROOT_ID, "file101", None),
+            false,
+            false,
+        );
+        assert_eq!(vffs.check_metadata_growth(&moved, None), Ok(()));
+
+        let longer = VFFS::rename_growth(
+            (FUSE_ROOT_ID, "file100", file_id),
+            (FUSE_ROOT_ID, "a longer name", None),
+            false,
+            false,
+        );
+        assert_eq!(vffs.check_metadata_growth(&longer, None), Err(libc::ENOSPC));
+
+        let whiteout = VFFS::rename_growth(
+            (FUSE_ROOT_ID, "file100", file_id),
+            (FUSE_ROOT_ID, "file101", None),
+            false,
+            true,
+        );
+        assert_eq!(
+            vffs.check_metadata_growth(&whiteout, None),
+            Err(libc::ENOSPC)
+        );
+    }
+
+    #[test]
+    fn extended_attributes_over_the_quota_of_the_owner_are_rejected() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let file_id = add_file(&mut vffs, 100, 1000);
+        let limits = QuotaLimits {
+            hard_bytes: vffs.lookup_node(file_id).unwrap().quota_bytes() + 64,
+            ..QuotaLimits::default()
+        };
+        vffs.quotas.set_limits(QuotaId::User(1000), limits);
+        let size = vffs.size;
+
+        let value = vec![7; 128];
+        assert_eq!(
+            vffs.set_xattr(1000, &[1000], file_id, b"user.big", &value, 0),
+            Err(libc::EDQUOT)
+        );
+        assert!(vffs.lookup_node(file_id).unwrap().xattrs.is_empty());
+        assert_eq!(vffs.size, size);
+
+        assert_eq!(
+            vffs.set_xattr(1000, &[1000], file_id, b"user.small", &[7], 0),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn sparse_files_report_only_their_allocated_blocks() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let file_id = add_file(&mut vffs, 100, 1000);
+
+        assert_eq!(vffs.write_file_data(file_id, 3 * CHUNK_SIZE, &[7]), Ok(()));
+        let attr: FileAttr = vffs.lookup_node(file_id).unwrap().into();
+        assert_eq!(attr.size, 3 * CHUNK_SIZE + 1);
+        assert_eq!(attr.blocks, CHUNK_SIZE / u64::from(BLOCK_SIZE));
+    }
+
+    #[test]
+    fn directory_cookies_stay_valid_when_entries_change_during_a_listing() {
+        let mut dir = Directory::new("dir".to_string(), FUSE_ROOT_ID);
+        for (id, name) in [(10, "a"), (11, "b"), (12, "c")] {
+            assert!(dir.add_node((id, name.to_string(), FileType::RegularFile)));
+        }
+
+        let listed: Vec<(u64, &str)> = dir
+            .entries_after(5, 0)
+            .take(3)
+            .map(|(cookie, _, name, _)| (cookie, name))
+            .collect();
+        assert_eq!(listed, vec![(1, "."), (2, ".."), (3, "a")]);
+        let offset = listed[2].0 as i64;
+
+        // Neither removing an entry already listed nor adding one skips or repeats the others
+        dir.remove_node_by_name("a");
+        assert!(dir.add_node((13, "d".to_string(), FileType::RegularFile)));
+        let rest: Vec<&str> = dir
+            .entries_after(5, offset)
+            .map(|(_, _, name, _)| name)
+            .collect();
+        assert_eq!(rest, vec!["b", "c", "d"]);
+
+        // Removing the entry after the cookie only drops that entry
+        dir.remove_node_by_name("b");
+        let rest: Vec<&str> = dir
+            .entries_after(5, offset)
+            .map(|(_, _, name, _)| name)
+            .collect();
+        assert_eq!(rest, vec!["c", "d"]);
+    }
+
+    #[test]
+    fn adding_a_duplicate_directory_entry_leaves_the_directory_unchanged() {
+        let mut dir = Directory::new("dir".to_string(), FUSE_ROOT_ID);
+        assert!(dir.add_node((10, "a".to_string(), FileType::RegularFile)));
+        let entries_size = dir.entries_size;
+
+        assert!(!dir.add_node((11, "a".to_string(), FileType::Directory)));
+        assert_eq!(dir.entries_size, entries_size);
+        assert_eq!(dir.nodes().count(), 1);
+        assert_eq!(
+            dir.find_node_by_name("a"),
+            Some((10, "a".to_string(), FileType::RegularFile))
+        );
+    }
+
+    #[test]
+    fn exchanging_a_directory_with_a_file_moves_its_parent_link() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let first_parent = add_entry(&mut vffs, FUSE_ROOT_ID, directory(10, FUSE_ROOT_ID, 0));
+        let second_parent = add_entry(&mut vffs, FUSE_ROOT_ID, directory(11, FUSE_ROOT_ID, 0));
+        let dir_id = add_entry(&mut vffs, first_parent, directory(12, first_parent, 0));
+        let file_id = add_entry(&mut vffs, second_parent, file(13, 0));
+        let size = vffs.size;
+
+        vffs.exchange_entries(
+            (first_parent, "dir12", dir_id),
+            (second_parent, "file13", file_id),
+        );
+
+        assert_eq!(vffs.lookup_node(first_parent).unwrap().hardlinks, 2);
+        assert_eq!(vffs.lookup_node(second_parent).unwrap().hardlinks, 3);
+        assert_eq!(vffs.lookup_node(dir_id).unwrap().get_name(), "file13");
+        assert_eq!(vffs.lookup_node(file_id).unwrap().get_name(), "dir12");
+        match &vffs.lookup_node(dir_id).unwrap().data {
+            InodeData::Directory(dir) => assert_eq!(dir.parent, second_parent),
+            _ => panic!("expected a directory"),
+        }
+        match &vffs.lookup_node(second_parent).unwrap().data {
+            InodeData::Directory(dir) => assert_eq!(
+                dir.find_node_by_name("file13"),
+                Some((dir_id, "file13".to_string(), FileType::Directory))
+            ),
+            _ => panic!("expected a directory"),
+        }
+        assert_eq!(vffs.size, size);
+    }
+
+    #[test]
+    fn retagging_a_subtree_moves_its_usage_to_the_new_project() {
+        let mut vffs = test_filesystem(Quotas::new(DEFAULT_GRACE_PERIOD));
+        let top_id = add_entry(&mut vffs, FUSE_ROOT_ID, directory(10, FUSE_ROOT_ID, 1000));
+        let file_id = add_entry(&mut vffs, top_id, file(11, 1000));
+        let nested_id = add_entry(&mut vffs, top_id, directory(12, top_id, 1000));
+        let nested_file_id = add_entry(&mut vffs, nested_id, file(13, 1000));
+        assert_eq!(vffs.write_file_data(file_id, 0, &[7; 100]), Ok(()));
+        assert_eq!(vffs.write_file_data(nested_file_id, 0, &[7; 100]), Ok(()));
+        let subtree_bytes: u64 = [top_id, file_id, nested_id, nested_file_id]
+            .iter()
+            .map(|id| vffs.lookup_node(*id).unwrap().quota_bytes())
+            .sum();
+
+        // Inodes already in the project are not charged to it a second time
+        assert_eq!(vffs.set_project(nested_id, 5), Ok(()));
+        assert_eq!(vffs.set_project(top_id, 5), Ok(()));
+        assert_eq!(vffs.quotas.usage(QuotaId::Project(5)), (subtree_bytes, 4));
+
+        assert_eq!(vffs.set_project(top_id, 6), Ok(()));
+        assert_eq!(vffs.quotas.usage(QuotaId::Project(5)), (0, 0));
+        assert_eq!(vffs.quotas.usage(QuotaId::Project(6)), (subtree_bytes, 4));
+        assert_eq!(vffs.lookup_node(nested_file_id).unwrap().project, 6);
+    }
 }
@@ -0,0 +1,320 @@
+use libc::c_int;
+use std::collections::HashMap;
+
+/// Default time a soft limit may stay exceeded before it is enforced, in seconds.
+pub const DEFAULT_GRACE_PERIOD: i64 = 7 * 24 * 60 * 60;
+
+/// Owner that bytes and inodes are charged to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuotaId {
+    User(u32),
+    Group(u32),
+    Project(u32),
+}
+
+/// Byte and inode limits of a quota, where 0 means no limit.
+/// A soft limit may be exceeded for the grace period, after which it is enforced
+/// like a hard limit until the usage drops below it again.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuotaLimits {
+    pub soft_bytes: u64,
+    pub hard_bytes: u64,
+    pub soft_inodes: u64,
+    pub hard_inodes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct QuotaUsage {
+    bytes: u64,
+    inodes: u64,
+    // When the usage went over the soft limits, which starts their grace period
+    bytes_over_soft_since: Option<i64>,
+    inodes_over_soft_since: Option<i64>,
+}
+
+/// Usage of every owner, along with the limits configured for some of them.
+#[derive(Debug, Clone)]
+pub struct Quotas {
+    limits: HashMap<QuotaId, QuotaLimits>,
+    usage: HashMap<QuotaId, QuotaUsage>,
+    grace_period: i64,
+}
+
+/// Whether adding `added` to `used` goes over a limit, given since when the usage
+/// has been over the soft limit.
+fn exceeds(
+    used: u64,
+    added: u64,
+    soft: u64,
+    hard: u64,
+    over_soft_since: Option<i64>,
+    grace_period: i64,
+    now: i64,
+) -> bool {
+    if added == 0 {
+        return false;
+    }
+
+    let new_usage = used + added;
+    if hard != 0 && new_usage > hard {
+        return true;
+    }
+    if soft != 0 && new_usage > soft {
+        if let Some(since) = over_soft_since {
+            return now - since >= grace_period;
+        }
+    }
+    false
+}
+
+impl Quotas {
+    pub fn new(grace_period: i64) -> Quotas {
+        Quotas {
+            limits: HashMap::new(),
+            usage: HashMap::new(),
+            grace_period,
+        }
+    }
+
+    pub fn set_limits(&mut self, id: QuotaId, limits: QuotaLimits) {
+        self.limits.insert(id, limits);
+    }
+
+    /// Parse a quota in the `<user|group|project>:<id>:<soft MB>:<hard MB>:<soft inodes>:<hard inodes>`
+    /// format, where a limit of 0 means no limit.
+    pub fn parse_spec(spec: &str) -> Result<(QuotaId, QuotaLimits), String> {
+        let fields: Vec<&str> = spec.trim().split(':').collect();
+        if fields.len() != 6 {
+            return Err(format!(
+                "Invalid quota \"{spec}\", expected <user|group|project>:<id>:<soft MB>:<hard MB>:<soft inodes>:<hard inodes>"
+            ));
+        }
+
+        let owner = match fields[1].parse::<u32>() {
+            Ok(owner) => owner,
+            Err(_) => return Err(format!("Invalid id \"{}\" in quota \"{spec}\"", fields[1])),
+        };
+        let id = match fields[0] {
+            "user" => QuotaId::User(owner),
+            "group" => QuotaId::Group(owner),
+            "project" => QuotaId::Project(owner),
+            kind => return Err(format!("Invalid quota kind \"{kind}\" in quota \"{spec}\"")),
+        };
+
+        let mut numbers = Vec::with_capacity(4);
+        for field in &fields[2..] {
+            match field.parse::<u64>() {
+                Ok(number) => numbers.push(number),
+                Err(_) => return Err(format!("Invalid number \"{field}\" in quota \"{spec}\"")),
+            }
+        }
+
+        let mut byte_limits = Vec::with_capacity(2);
+        for megabytes in &numbers[..2] {
+            match megabytes.checked_mul(1024 * 1024) {
+                Some(bytes) => byte_limits.push(bytes),
+                None => {
+                    return Err(format!(
+                        "Limit of {megabytes} MB is too large in quota \"{spec}\""
+                    ))
+                }
+            }
+        }
+
+        let limits = QuotaLimits {
+            soft_bytes: byte_limits[0],
+            hard_bytes: byte_limits[1],
+            soft_inodes: numbers[2],
+            hard_inodes: numbers[3],
+        };
+        Ok((id, limits))
+    }
+
+    /// Read quotas from a file holding one quota per line, in the format of `parse_spec`.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse_file(path: &str) -> Result<Vec<(QuotaId, QuotaLimits)>, String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) => return Err(format!("Could not read quota file {path}: {err}")),
+        };
+
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Quotas::parse_spec)
+            .collect()
+    }
+
+    /// Bytes and inodes currently charged to an owner.
+    pub fn usage(&self, id: QuotaId) -> (u64, u64) {
+        match self.usage.get(&id) {
+            Some(usage) => (usage.bytes, usage.inodes),
+            None => (0, 0),
+        }
+    }
+
+    /// Check that charging `bytes` and `inodes` more to each of the owners stays within their limits.
+    pub fn check(&self, ids: &[QuotaId], bytes: u64, inodes: u64, now: i64) -> Result<(), c_int> {
+        for id in ids {
+            let limits = match self.limits.get(id) {
+                Some(limits) => limits,
+                None => continue,
+            };
+            let usage = self.usage.get(id).cloned().unwrap_or_default();
+
+            if exceeds(
+                usage.bytes,
+                bytes,
+                limits.soft_bytes,
+                limits.hard_bytes,
+                usage.bytes_over_soft_since,
+                self.grace_period,
+                now,
+            ) || exceeds(
+                usage.inodes,
+                inodes,
+                limits.soft_inodes,
+                limits.hard_inodes,
+                usage.inodes_over_soft_since,
+                self.grace_period,
+                now,
+            ) {
+                return Err(libc::EDQUOT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `bytes` and `inodes`, which may be negative, to the usage of each of the owners.
+    pub fn charge(&mut self, ids: &[QuotaId], bytes: i64, inodes: i64, now: i64) {
+        for id in ids {
+            let limits = self.limits.get(id).copied().unwrap_or_default();
+            let usage = self.usage.entry(*id).or_default();
+
+            usage.bytes = usage.bytes.saturating_add_signed(bytes);
+            usage.inodes = usage.inodes.saturating_add_signed(inodes);
+
+            if limits.soft_bytes != 0 && usage.bytes > limits.soft_bytes {
+                usage.bytes_over_soft_since.get_or_insert(now);
+            } else {
+                usage.bytes_over_soft_since = None;
+            }
+            if limits.soft_inodes != 0 && usage.inodes > limits.soft_inodes {
+                usage.inodes_over_soft_since.get_or_insert(now);
+            } else {
+                usage.inodes_over_soft_since = None;
+            }
+        }
+    }
+
+    /// Describe the usage and limits of each of the owners, one per line.
+    pub fn report(&self, ids: &[QuotaId], now: i64) -> String {
+        let mut report = String::new();
+        for id in ids {
+            let limits = self.limits.get(id).copied().unwrap_or_default();
+            let usage = self.usage.get(id).cloned().unwrap_or_default();
+
+            let owner = match id {
+                QuotaId::User(uid) => format!("user {uid}"),
+                QuotaId::Group(gid) => format!("group {gid}"),
+                QuotaId::Project(project) => format!("project {project}"),
+            };
+            report.push_str(&format!(
+                "{owner}: bytes {} (soft {}, hard {}), inodes {} (soft {}, hard {})",
+                usage.bytes,
+                limits.soft_bytes,
+                limits.hard_bytes,
+                usage.inodes,
+                limits.soft_inodes,
+                limits.hard_inodes,
+            ));
+
+            let grace_left = [usage.bytes_over_soft_since, usage.inodes_over_soft_since]
+                .into_iter()
+                .flatten()
+                .map(|since| (since + self.grace_period - now).max(0))
+                .min();
+            if let Some(seconds) = grace_left {
+                report.push_str(&format!(", grace {seconds}s left"));
+            }
+            report.push('\n');
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_spec_reads_limits_in_megabytes() {
+        let (id, limits) = Quotas::parse_spec("group:100:1:2:3:4").unwrap();
+        assert_eq!(id, QuotaId::Group(100));
+        assert_eq!(limits.soft_bytes, 1024 * 1024);
+        assert_eq!(limits.hard_bytes, 2 * 1024 * 1024);
+        assert_eq!(limits.soft_inodes, 3);
+        assert_eq!(limits.hard_inodes, 4);
+    }
+
+    #[test]
+    fn parse_spec_rejects_malformed_quotas() {
+        assert!(Quotas::parse_spec("user:1000:1:2:3").is_err());
+        assert!(Quotas::parse_spec("owner:1000:1:2:3:4").is_err());
+        assert!(Quotas::parse_spec("user:1000:1:x:3:4").is_err());
+    }
+
+    #[test]
+    fn parse_spec_rejects_ids_and_limits_out_of_range() {
+        assert!(Quotas::parse_spec("user:4294967296:1:2:3:4").is_err());
+        assert!(Quotas::parse_spec("user:4294967295:1:2:3:4").is_ok());
+        assert!(Quotas::parse_spec(&format!("user:1000:{}:0:0:0", u64::MAX / 1024)).is_err());
+    }
+
+    #[test]
+    fn check_enforces_hard_limits_of_every_owner() {
+        let mut quotas = Quotas::new(DEFAULT_GRACE_PERIOD);
+        let limits = QuotaLimits {
+            hard_bytes: 100,
+            hard_inodes: 2,
+            ..QuotaLimits::default()
+        };
+        quotas.set_limits(QuotaId::Group(10), limits);
+        let ids = [QuotaId::User(1000), QuotaId::Group(10)];
+
+        quotas.charge(&ids, 60, 1, 0);
+        assert_eq!(quotas.check(&ids, 40, 1, 0), Ok(()));
+        assert_eq!(quotas.check(&ids, 41, 0, 0), Err(libc::EDQUOT));
+        assert_eq!(quotas.check(&ids, 0, 2, 0), Err(libc::EDQUOT));
+
+        // Owners without limits are only tracked
+        assert_eq!(quotas.check(&[QuotaId::User(1000)], 1000, 10, 0), Ok(()));
+        assert_eq!(quotas.usage(QuotaId::User(1000)), (60, 1));
+    }
+
+    #[test]
+    fn check_enforces_soft_limits_once_the_grace_period_ends() {
+        let mut quotas = Quotas::new(100);
+        let limits = QuotaLimits {
+            soft_bytes: 50,
+            ..QuotaLimits::default()
+        };
+        let ids = [QuotaId::User(1000)];
+        quotas.set_limits(ids[0], limits);
+
+        quotas.charge(&ids, 60, 0, 1000);
+        assert_eq!(quotas.check(&ids, 10, 0, 1099), Ok(()));
+        assert_eq!(quotas.check(&ids, 10, 0, 1100), Err(libc::EDQUOT));
+
+        // Changes that add nothing are always allowed
+        assert_eq!(quotas.check(&ids, 0, 0, 1100), Ok(()));
+
+        // Going back under the soft limit restarts the grace period next time
+        quotas.charge(&ids, -20, 0, 1200);
+        assert_eq!(quotas.check(&ids, 10, 0, 1200), Ok(()));
+        quotas.charge(&ids, 20, 0, 1300);
+        assert_eq!(quotas.check(&ids, 10, 0, 1399), Ok(()));
+        assert_eq!(quotas.check(&ids, 10, 0, 1400), Err(libc::EDQUOT));
+    }
+}
@@ -22,3 +22,23 @@ pub fn time_from_system_time(system_time: &SystemTime) -> (i64, u32) {
         ),
     }
 }
+
+/// Read the supplementary group IDs of a process from `/proc/<pid>/status`.
+/// Returns an empty list when the process is gone or the file cannot be parsed.
+pub fn supplementary_groups(pid: u32) -> Vec<u32> {
+    let status = match std::fs::read_to_string(format!("/proc/{pid}/status")) {
+        Ok(status) => status,
+        Err(_) => return Vec::new(),
+    };
+
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|group| group.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}